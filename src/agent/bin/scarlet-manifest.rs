@@ -0,0 +1,120 @@
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use scarlet::manifest::{self, GeneratorConfig, Manifest};
+
+const USAGE: &str =
+    "Usage: scarlet-manifest <folder> [--output <file>] [--previous <file>] [--jobs <n>]
+
+Hashes every file under <folder> and writes a theupdates manifest to <file>,
+or to stdout. Hashes are reused from the previous manifest for files whose
+size and mtime are unchanged; an existing <file> is used as the previous
+manifest unless --previous is given.";
+
+struct Args {
+    folder: PathBuf,
+    output: Option<PathBuf>,
+    previous: Option<PathBuf>,
+    jobs: Option<usize>,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = std::env::args().skip(1);
+    let mut folder = None;
+    let mut output = None;
+    let mut previous = None;
+    let mut jobs = None;
+
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or_else(|| format!("{} needs a value", name));
+        match arg.as_str() {
+            "--output" | "-o" => output = Some(PathBuf::from(value(&arg)?)),
+            "--previous" => previous = Some(PathBuf::from(value(&arg)?)),
+            "--jobs" | "-j" => {
+                let count = value(&arg)?;
+                jobs = Some(
+                    count
+                        .parse()
+                        .map_err(|_| format!("--jobs expects a number, got {}", count))?,
+                );
+            }
+            "--help" | "-h" => return Err(String::new()),
+            _ if arg.starts_with('-') => return Err(format!("Unknown option {}", arg)),
+            _ if folder.is_none() => folder = Some(PathBuf::from(arg)),
+            _ => return Err(format!("Unexpected argument {}", arg)),
+        }
+    }
+
+    Ok(Args {
+        folder: folder.ok_or("Missing <folder>")?,
+        output,
+        previous,
+        jobs,
+    })
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(message) => {
+            if !message.is_empty() {
+                eprintln!("{}\n", message);
+            }
+            eprintln!("{}", USAGE);
+            return ExitCode::from(2);
+        }
+    };
+
+    let mut config = GeneratorConfig::default();
+    if let Some(jobs) = args.jobs {
+        config.concurrency = jobs;
+    }
+
+    let previous_path = args
+        .previous
+        .clone()
+        .or_else(|| args.output.clone().filter(|output| output.exists()));
+    if let Some(path) = &previous_path {
+        let previous = std::fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|xml| Manifest::parse(&xml).map_err(|e| e.to_string()));
+        match previous {
+            Ok(previous) => config.previous = Some(previous),
+            Err(e) => {
+                eprintln!("Failed to read previous manifest {:?}: {}", path, e);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+
+    // The manifest is usually written into the folder it describes
+    let excluded = args.output.as_ref().and_then(|output| {
+        let output = output.canonicalize().ok()?;
+        let folder = args.folder.canonicalize().ok()?;
+        output.strip_prefix(folder).ok().map(PathBuf::from)
+    });
+    config.exclude.extend(excluded);
+
+    let manifest = match manifest::generate(&args.folder, config).await {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            eprintln!("Failed to generate manifest for {:?}: {}", args.folder, e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let xml = manifest.to_xml();
+    match &args.output {
+        Some(output) => {
+            if let Err(e) = std::fs::write(output, xml) {
+                eprintln!("Failed to write {:?}: {}", output, e);
+                return ExitCode::FAILURE;
+            }
+            eprintln!("Wrote {} files to {:?}", manifest.entries.len(), output);
+        }
+        None => print!("{}", xml),
+    }
+
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,538 @@
+use std::collections::HashSet;
+use std::future::Future;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde_json::{json, Value};
+use tokio::sync::broadcast::error::RecvError;
+
+use scarlet::download::{
+    expected_files, DownloadConfig, DownloadError, DownloadEvent, DownloadManager,
+    DownloadProgress, FileToDownload, SyncPlan, SyncSummary,
+};
+use scarlet::manifest;
+
+const USAGE: &str = "Usage: scarlet-sync <command> <destination> --manifest <url> [options]
+
+Commands:
+  sync     Download what's missing or changed, then remove files the
+           manifest no longer lists
+  verify   Rehash every file and report anything out of date
+  plan     Show what sync would do without changing anything
+  clean    Only remove files the manifest no longer lists
+
+Options:
+  --manifest <url>        theupdates manifest of the repository
+  --json                  Print JSON lines instead of text
+  --concurrency <n>       Files downloaded at the same time
+  --mirror <url>          Extra repository mirror, may be repeated
+  --rate-limit <bytes/s>  Cap download speed
+  --cleanup <policy>      delete (default), trash or quarantine files the
+                          manifest no longer lists
+  --dry-run               With clean, list what would be removed and keep it
+
+Exit codes:
+  0    Everything is in sync
+  1    Some files failed to download, or verify found files out of date
+  2    Bad arguments
+  3    The manifest couldn't be fetched or read
+  4    The command was aborted by an error
+  130  Interrupted";
+
+const EXIT_OUT_OF_SYNC: u8 = 1;
+const EXIT_USAGE: u8 = 2;
+const EXIT_MANIFEST: u8 = 3;
+const EXIT_ABORTED: u8 = 4;
+const EXIT_INTERRUPTED: u8 = 130;
+
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Command {
+    Sync,
+    Verify,
+    Plan,
+    Clean,
+}
+
+struct Args {
+    command: Command,
+    destination: PathBuf,
+    manifest_url: String,
+    json: bool,
+    dry_run: bool,
+    config: DownloadConfig,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = std::env::args().skip(1);
+    let mut command = None;
+    let mut destination = None;
+    let mut manifest_url = None;
+    let mut json = false;
+    let mut dry_run = false;
+    let mut config = DownloadConfig::default();
+
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or_else(|| format!("{} needs a value", name));
+        match arg.as_str() {
+            "--manifest" | "-m" => manifest_url = Some(value(&arg)?),
+            "--json" => json = true,
+            "--dry-run" => dry_run = true,
+            "--concurrency" | "-c" => {
+                config.max_concurrent_downloads = parse_number(&arg, &value(&arg)?)?;
+            }
+            "--mirror" => config.mirrors.push(value(&arg)?),
+            "--rate-limit" => {
+                config.rate_limit.bytes_per_second = Some(parse_number(&arg, &value(&arg)?)?);
+            }
+            "--cleanup" => config.cleanup_policy = value(&arg)?.parse()?,
+            "--help" | "-h" => return Err(String::new()),
+            _ if arg.starts_with('-') => return Err(format!("Unknown option {}", arg)),
+            _ if command.is_none() => {
+                command = Some(match arg.as_str() {
+                    "sync" => Command::Sync,
+                    "verify" => Command::Verify,
+                    "plan" => Command::Plan,
+                    "clean" => Command::Clean,
+                    _ => return Err(format!("Unknown command {}", arg)),
+                });
+            }
+            _ if destination.is_none() => destination = Some(PathBuf::from(arg)),
+            _ => return Err(format!("Unexpected argument {}", arg)),
+        }
+    }
+
+    let command = command.ok_or("Missing <command>")?;
+    if dry_run && command != Command::Clean {
+        return Err("--dry-run only applies to clean, use plan to preview a sync".to_string());
+    }
+
+    Ok(Args {
+        command,
+        destination: destination.ok_or("Missing <destination>")?,
+        manifest_url: manifest_url.ok_or("Missing --manifest")?,
+        json,
+        dry_run,
+        config,
+    })
+}
+
+fn parse_number<T: std::str::FromStr>(option: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("{} expects a number, got {}", option, value))
+}
+
+/// Writes results either as text for a person or as one JSON object per line.
+struct Output {
+    json: bool,
+    /// Whether a progress line is on screen and must be cleared first.
+    progress_shown: bool,
+}
+
+impl Output {
+    fn line(&mut self, text: impl AsRef<str>, value: Value) {
+        if self.json {
+            println!("{}", value);
+        } else {
+            self.clear_progress();
+            println!("{}", text.as_ref());
+        }
+    }
+
+    fn error(&mut self, text: impl AsRef<str>) {
+        if self.json {
+            println!("{}", json!({ "event": "error", "message": text.as_ref() }));
+        } else {
+            self.clear_progress();
+            eprintln!("{}", text.as_ref());
+        }
+    }
+
+    fn progress(&mut self, progress: &DownloadProgress) {
+        if self.json {
+            println!(
+                "{}",
+                json!({
+                    "event": "progress",
+                    "status": format!("{:?}", progress.status),
+                    "filesTotal": progress.files_total,
+                    "filesCompleted": progress.files_total_completed,
+                    "bytesTotal": progress.bytes_total,
+                    "bytesCompleted": progress.bytes_completed,
+                    "bytesPerSecond": progress.throughput,
+                    "etaSeconds": progress.eta_seconds,
+                })
+            );
+        } else if std::io::stderr().is_terminal() {
+            let percent = if progress.bytes_total > 0 {
+                progress.bytes_completed as f64 / progress.bytes_total as f64 * 100.0
+            } else {
+                0.0
+            };
+            eprint!(
+                "\r\x1b[K{:?} {:>5.1}%  {}/{} files  {}/s",
+                progress.status,
+                percent,
+                progress.files_total_completed,
+                progress.files_total,
+                format_bytes(progress.throughput as u64)
+            );
+            self.progress_shown = true;
+        }
+    }
+
+    fn clear_progress(&mut self) {
+        if self.progress_shown {
+            eprint!("\r\x1b[K");
+            self.progress_shown = false;
+        }
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+fn file_paths(files: &[FileToDownload]) -> Vec<&str> {
+    files.iter().map(|file| file.path.as_str()).collect()
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(message) => {
+            if !message.is_empty() {
+                eprintln!("{}\n", message);
+            }
+            eprintln!("{}", USAGE);
+            return ExitCode::from(EXIT_USAGE);
+        }
+    };
+
+    let mut output = Output {
+        json: args.json,
+        progress_shown: false,
+    };
+
+    let files = match manifest::fetch(&manifest::client(), &args.manifest_url)
+        .await
+        .and_then(|manifest| manifest.files(&args.manifest_url))
+    {
+        Ok(files) => files,
+        Err(e) => {
+            output.error(format!("Failed to load manifest: {}", e));
+            return ExitCode::from(EXIT_MANIFEST);
+        }
+    };
+
+    let mut config = args.config.clone();
+    // Verifying means not trusting hashes remembered from earlier runs
+    config.force_rehash = args.command == Command::Verify;
+    let manager = Arc::new(DownloadManager::with_config(config));
+
+    let interrupted = {
+        let manager = manager.clone();
+        tokio::spawn(async move {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                manager.cancel();
+            }
+        })
+    };
+
+    let code = match args.command {
+        Command::Sync => sync(&manager, &args.destination, files, &mut output).await,
+        Command::Verify | Command::Plan => {
+            let result = watch(
+                &manager,
+                manager.plan(&args.destination, &files),
+                &mut output,
+            )
+            .await;
+            match result {
+                Ok(plan) => report_plan(&plan, args.command, &mut output),
+                Err(DownloadError::Cancelled) => {
+                    output.error("Interrupted");
+                    EXIT_INTERRUPTED
+                }
+                Err(e) => {
+                    output.error(format!("Failed to check {:?}: {}", args.destination, e));
+                    EXIT_ABORTED
+                }
+            }
+        }
+        Command::Clean => {
+            let expected = expected_files(&files);
+            if args.dry_run {
+                preview_clean(&manager, &args.destination, &expected, &mut output).await
+            } else {
+                clean(&manager, &args.destination, &expected, &mut output).await
+            }
+        }
+    };
+
+    interrupted.abort();
+    ExitCode::from(code)
+}
+
+async fn clean(
+    manager: &DownloadManager,
+    destination: &Path,
+    expected: &HashSet<PathBuf>,
+    output: &mut Output,
+) -> u8 {
+    match manager.cleanup_files(destination, expected).await {
+        Ok(removed) => {
+            for path in &removed {
+                output.line(
+                    format!("Removed {}", path.display()),
+                    json!({ "event": "removed", "path": path }),
+                );
+            }
+            output.line(
+                format!("Removed {} files", removed.len()),
+                json!({ "event": "cleaned", "removed": removed.len() }),
+            );
+            0
+        }
+        Err(e) => {
+            output.error(format!("Cleanup failed: {}", e));
+            EXIT_ABORTED
+        }
+    }
+}
+
+async fn preview_clean(
+    manager: &DownloadManager,
+    destination: &Path,
+    expected: &HashSet<PathBuf>,
+    output: &mut Output,
+) -> u8 {
+    let entries = match manager.preview_cleanup(destination, expected).await {
+        Ok(entries) => entries,
+        Err(e) => {
+            output.error(format!("Failed to check {:?}: {}", destination, e));
+            return EXIT_ABORTED;
+        }
+    };
+
+    for entry in &entries {
+        let kind = if entry.is_dir { "folder " } else { "" };
+        output.line(
+            format!(
+                "Would remove {}{} ({} bytes, in {})",
+                kind,
+                entry.path.display(),
+                entry.size,
+                entry.managed_dir.display()
+            ),
+            json!({
+                "event": "wouldRemove",
+                "path": entry.path,
+                "isDirectory": entry.is_dir,
+                "size": entry.size,
+                "managedFolder": entry.managed_dir,
+            }),
+        );
+    }
+    let bytes: u64 = entries
+        .iter()
+        .filter(|entry| !entry.is_dir)
+        .map(|entry| entry.size)
+        .sum();
+    output.line(
+        format!("Would remove {} paths, {} bytes", entries.len(), bytes),
+        json!({ "event": "cleanPreview", "paths": entries.len(), "bytes": bytes }),
+    );
+    0
+}
+
+async fn sync(
+    manager: &Arc<DownloadManager>,
+    destination: &Path,
+    files: Vec<FileToDownload>,
+    output: &mut Output,
+) -> u8 {
+    match watch(manager, manager.download(destination, files), output).await {
+        Ok(summary) => report_summary(&summary, output),
+        Err(DownloadError::Cancelled) => {
+            output.error("Interrupted");
+            EXIT_INTERRUPTED
+        }
+        Err(e) => {
+            output.error(format!("Sync failed: {}", e));
+            EXIT_ABORTED
+        }
+    }
+}
+
+/// Drives `work` to completion while reporting the manager's events and,
+/// every `PROGRESS_INTERVAL`, its progress.
+async fn watch<T>(
+    manager: &DownloadManager,
+    work: impl Future<Output = T>,
+    output: &mut Output,
+) -> T {
+    let mut events = manager.subscribe();
+    tokio::pin!(work);
+
+    let mut ticker = tokio::time::interval(PROGRESS_INTERVAL);
+    let result = loop {
+        tokio::select! {
+            result = &mut work => break result,
+            event = events.recv() => match event {
+                Ok(event) => report_event(&event, output),
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => {}
+            },
+            _ = ticker.tick() => {
+                output.progress(&manager.get_progress().await);
+            }
+        }
+    };
+    while let Ok(event) = events.try_recv() {
+        report_event(&event, output);
+    }
+    result
+}
+
+fn report_event(event: &DownloadEvent, output: &mut Output) {
+    match event {
+        DownloadEvent::FileCompleted { path } => output.line(
+            format!("Downloaded {}", path),
+            json!({ "event": "fileCompleted", "path": path }),
+        ),
+        DownloadEvent::FileFailed { path, error } => output.line(
+            format!("Failed {}: {}", path, error),
+            json!({ "event": "fileFailed", "path": path, "error": error }),
+        ),
+        DownloadEvent::HashIndexFailed { error } => {
+            output.error(format!("Failed to save hash index: {}", error))
+        }
+        DownloadEvent::CleanupRemoved { path } => output.line(
+            format!("Removed {}", path.display()),
+            json!({ "event": "removed", "path": path }),
+        ),
+        // Started and finished events are only interesting as JSON
+        DownloadEvent::FileStarted { path } if output.json => {
+            output.line("", json!({ "event": "fileStarted", "path": path }))
+        }
+        DownloadEvent::VerificationStarted { path } if output.json => {
+            output.line("", json!({ "event": "verificationStarted", "path": path }))
+        }
+        _ => {}
+    }
+}
+
+fn report_summary(summary: &SyncSummary, output: &mut Output) -> u8 {
+    let errors: Vec<Value> = summary
+        .errors
+        .iter()
+        .map(|error| json!({ "path": error.path, "code": error.code, "message": error.message }))
+        .collect();
+    output.line(
+        format!(
+            "Downloaded {}, up to date {}, failed {}, removed {} ({} in {:.1}s)",
+            summary.downloaded,
+            summary.skipped,
+            summary.failed,
+            summary.removed,
+            format_bytes(summary.bytes_transferred),
+            summary.duration.as_secs_f64()
+        ),
+        json!({
+            "event": "summary",
+            "downloaded": summary.downloaded,
+            "skipped": summary.skipped,
+            "failed": summary.failed,
+            "removed": summary.removed,
+            "bytesTransferred": summary.bytes_transferred,
+            "durationMs": summary.duration.as_millis() as u64,
+            "errors": errors,
+        }),
+    );
+
+    if summary.failed > 0 {
+        EXIT_OUT_OF_SYNC
+    } else {
+        0
+    }
+}
+
+fn report_plan(plan: &SyncPlan, command: Command, output: &mut Output) -> u8 {
+    for file in &plan.missing {
+        output.line(
+            format!("Missing {}", file.path),
+            json!({ "event": "missing", "path": file.path }),
+        );
+    }
+    for file in &plan.mismatched {
+        output.line(
+            format!("Changed {}", file.path),
+            json!({ "event": "mismatched", "path": file.path }),
+        );
+    }
+    for error in &plan.rejected {
+        output.line(
+            format!("Rejected {}: {}", error.path, error.message),
+            json!({ "event": "rejected", "path": error.path, "code": error.code, "message": error.message }),
+        );
+    }
+    for path in &plan.extra {
+        output.line(
+            format!("Extra {}", path.display()),
+            json!({ "event": "extra", "path": path }),
+        );
+    }
+
+    output.line(
+        format!(
+            "{} up to date, {} missing, {} changed ({} to download), {} extra ({})",
+            plan.up_to_date.len(),
+            plan.missing.len(),
+            plan.mismatched.len(),
+            format_bytes(
+                plan.files_to_download()
+                    .map(|file| file.size.unwrap_or(0))
+                    .sum()
+            ),
+            plan.extra.len(),
+            format_bytes(plan.extra_bytes)
+        ),
+        json!({
+            "event": "plan",
+            "upToDate": file_paths(&plan.up_to_date),
+            "missing": file_paths(&plan.missing),
+            "mismatched": file_paths(&plan.mismatched),
+            "extra": plan.extra,
+            "rejected": plan.rejected.iter().map(|error| &error.path).collect::<Vec<_>>(),
+            "upToDateBytes": plan.up_to_date_bytes,
+            "mismatchedBytes": plan.mismatched_bytes,
+            "extraBytes": plan.extra_bytes,
+        }),
+    );
+
+    let out_of_date =
+        !plan.missing.is_empty() || !plan.mismatched.is_empty() || !plan.rejected.is_empty();
+    if command == Command::Verify && out_of_date {
+        EXIT_OUT_OF_SYNC
+    } else {
+        0
+    }
+}
@@ -0,0 +1,227 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use walkdir::WalkDir;
+
+/// Folder inside the destination holding one snapshot per quarantining cleanup.
+pub const QUARANTINE_DIR_NAME: &str = ".scarlet-quarantine";
+
+/// Snapshot folders are named after the time they were taken, in UTC. Colons
+/// aren't allowed on Windows, hence the dashes.
+const SNAPSHOT_FORMAT: &str = "%Y-%m-%dT%H-%M-%S";
+
+/// What cleanup does with files the manifest no longer lists.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum CleanupPolicy {
+    #[default]
+    Delete,
+    /// Move to the operating system's trash or recycle bin.
+    Trash,
+    /// Move into a timestamped snapshot under the destination's quarantine folder.
+    Quarantine,
+}
+
+impl std::str::FromStr for CleanupPolicy {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "delete" => Ok(CleanupPolicy::Delete),
+            "trash" => Ok(CleanupPolicy::Trash),
+            "quarantine" => Ok(CleanupPolicy::Quarantine),
+            _ => Err(format!("Unknown cleanup policy {}", name)),
+        }
+    }
+}
+
+/// Takes unexpected paths out of the destination according to a policy.
+pub struct Remover {
+    policy: CleanupPolicy,
+    destination: PathBuf,
+    /// Created on first use, so a cleanup with nothing to remove leaves no trace.
+    snapshot: Option<PathBuf>,
+}
+
+impl Remover {
+    pub fn new(destination: &Path, policy: CleanupPolicy) -> Self {
+        Self {
+            policy,
+            destination: destination.to_path_buf(),
+            snapshot: None,
+        }
+    }
+
+    pub fn remove_file(&mut self, path: &Path) -> io::Result<()> {
+        match self.policy {
+            CleanupPolicy::Delete => fs::remove_file(path),
+            CleanupPolicy::Trash => trash::delete(path).map_err(io::Error::other),
+            CleanupPolicy::Quarantine => {
+                let relative = path.strip_prefix(&self.destination).map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("{:?} is outside the destination", path),
+                    )
+                })?;
+                let target = self.snapshot()?.join(relative);
+                if let Some(parent) = target.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::rename(path, target)
+            }
+        }
+    }
+
+    /// Empty folders carry nothing worth keeping; restoring recreates them.
+    pub fn remove_empty_dir(&mut self, path: &Path) -> io::Result<()> {
+        fs::remove_dir(path)
+    }
+
+    fn snapshot(&mut self) -> io::Result<&Path> {
+        if self.snapshot.is_none() {
+            let root = self.destination.join(QUARANTINE_DIR_NAME);
+            let name = Utc::now().format(SNAPSHOT_FORMAT).to_string();
+
+            // Two cleanups within a second get their own snapshots
+            let mut snapshot = root.join(&name);
+            let mut suffix = 2;
+            while snapshot.exists() {
+                snapshot = root.join(format!("{}-{}", name, suffix));
+                suffix += 1;
+            }
+            fs::create_dir_all(&snapshot)?;
+            self.snapshot = Some(snapshot);
+        }
+        Ok(self.snapshot.as_deref().unwrap())
+    }
+}
+
+/// Files moved aside by one cleanup.
+#[derive(Debug, Clone)]
+pub struct QuarantineSnapshot {
+    pub id: String,
+    pub created: DateTime<Utc>,
+    /// Paths relative to the destination, with their sizes.
+    pub files: Vec<(PathBuf, u64)>,
+}
+
+fn snapshot_created(id: &str) -> Option<DateTime<Utc>> {
+    let timestamp = id.get(..19)?;
+    NaiveDateTime::parse_from_str(timestamp, SNAPSHOT_FORMAT)
+        .ok()
+        .map(|time| time.and_utc())
+}
+
+/// Quarantine snapshots in `destination`, oldest first. Folders that don't
+/// look like snapshots are left alone.
+pub fn list_snapshots(destination: &Path) -> io::Result<Vec<QuarantineSnapshot>> {
+    let root = destination.join(QUARANTINE_DIR_NAME);
+    let entries = match fs::read_dir(&root) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut snapshots = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let id = entry.file_name().to_string_lossy().into_owned();
+        let created = match snapshot_created(&id) {
+            Some(created) if entry.file_type()?.is_dir() => created,
+            _ => continue,
+        };
+
+        let mut files = Vec::new();
+        for file in WalkDir::new(entry.path()).sort_by_file_name() {
+            let file = file?;
+            if file.file_type().is_dir() {
+                continue;
+            }
+            let relative = file
+                .path()
+                .strip_prefix(entry.path())
+                .unwrap_or(file.path());
+            files.push((relative.to_path_buf(), file.metadata()?.len()));
+        }
+
+        snapshots.push(QuarantineSnapshot { id, created, files });
+    }
+
+    snapshots.sort_by(|a, b| a.created.cmp(&b.created).then(a.id.cmp(&b.id)));
+    Ok(snapshots)
+}
+
+/// Moves files from snapshot `id` back into the destination, all of them or
+/// only `paths`. Files that have since reappeared in the destination are
+/// left in the snapshot rather than overwritten. Returns the restored paths.
+/// Restored files the manifest doesn't list are taken out again by the next
+/// cleanup, so this is for getting them back to move somewhere safe.
+pub fn restore(
+    destination: &Path,
+    id: &str,
+    paths: Option<&[PathBuf]>,
+) -> io::Result<Vec<PathBuf>> {
+    let snapshot = list_snapshots(destination)?
+        .into_iter()
+        .find(|snapshot| snapshot.id == id)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No quarantine snapshot {}", id),
+            )
+        })?;
+    let snapshot_dir = destination.join(QUARANTINE_DIR_NAME).join(&snapshot.id);
+
+    let mut restored = Vec::new();
+    for (relative, _) in snapshot.files {
+        if paths.is_some_and(|paths| !paths.contains(&relative)) {
+            continue;
+        }
+        let target = destination.join(&relative);
+        if target.exists() {
+            continue;
+        }
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(snapshot_dir.join(&relative), &target)?;
+        restored.push(relative);
+    }
+
+    remove_empty_dirs(&snapshot_dir)?;
+    Ok(restored)
+}
+
+/// Deletes snapshots taken more than `retention` ago, returning their ids.
+pub fn expire(destination: &Path, retention: Duration) -> io::Result<Vec<String>> {
+    // A retention too long to represent never expires anything
+    let cutoff = match chrono::Duration::from_std(retention)
+        .ok()
+        .and_then(|retention| Utc::now().checked_sub_signed(retention))
+    {
+        Some(cutoff) => cutoff,
+        None => return Ok(Vec::new()),
+    };
+
+    let mut expired = Vec::new();
+    for snapshot in list_snapshots(destination)? {
+        if snapshot.created < cutoff {
+            fs::remove_dir_all(destination.join(QUARANTINE_DIR_NAME).join(&snapshot.id))?;
+            expired.push(snapshot.id);
+        }
+    }
+    Ok(expired)
+}
+
+/// Removes `dir` and any folders under it that hold no files.
+fn remove_empty_dirs(dir: &Path) -> io::Result<()> {
+    for entry in WalkDir::new(dir).contents_first(true) {
+        let entry = entry?;
+        if entry.file_type().is_dir() && fs::read_dir(entry.path())?.next().is_none() {
+            fs::remove_dir(entry.path())?;
+        }
+    }
+    Ok(())
+}
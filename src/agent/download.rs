@@ -1,26 +1,46 @@
 use std::collections::{HashSet, HashMap};
 use std::fs::{self, File};
-use std::io::Write;
-use std::path::{Path, PathBuf};
+use std::io::SeekFrom;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
-use futures::StreamExt;
-use reqwest::Client;
+use futures::{stream, Stream, StreamExt};
+use rand::Rng;
+use reqwest::header::{
+    HeaderMap, ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, ETAG, IF_RANGE, LAST_MODIFIED, RANGE,
+    RETRY_AFTER,
+};
+use reqwest::{Client, RequestBuilder, Response, StatusCode};
 use sha2::{Digest, Sha256};
 use thiserror::Error;
-use tokio::sync::Mutex;
+use tokio::fs::OpenOptions;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::{broadcast, watch, Mutex};
 use walkdir::WalkDir;
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+use crate::cleanup::{self, CleanupPolicy, Remover, QUARANTINE_DIR_NAME};
+use crate::hash_cache::{FileFingerprint, HashCache, INDEX_FILE_NAME};
+use crate::mirrors::{
+    apply_ranking, probe, probe_targets, rank, sources_for, MirrorHealth, MirrorProbe, Source,
+};
+use crate::rate_limit::{RateLimit, RateLimiter};
+use crate::segments::{self, Segment};
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
 pub enum DownloadStatus {
+    #[default]
     Ready,
     Initiating,
+    Scanning,
     Downloading,
+    Paused,
     Done,
     Error,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct DownloadProgress {
     pub status: DownloadStatus,
     pub files_total: usize,
@@ -29,7 +49,76 @@ pub struct DownloadProgress {
     pub current_file_downloaded: u64,
     pub current_file_total_size: u64,
     pub current_file_path: String,
-    pub failed_files: HashMap<String, String>,
+    pub failed_files: HashMap<String, FileError>,
+    pub active_files: HashMap<String, FileProgress>,
+    pub served_by: HashMap<String, String>,
+    pub scan_files_total: usize,
+    pub scan_files_completed: usize,
+    pub scan_bytes_completed: u64,
+    pub bytes_transferred: u64,
+    pub bytes_total: u64,
+    pub bytes_completed: u64,
+    /// Smoothed transfer rate in bytes per second.
+    pub throughput: f64,
+    pub eta_seconds: Option<f64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct FileError {
+    pub path: String,
+    /// Stable identifier from `DownloadError::code`.
+    pub code: &'static str,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SyncSummary {
+    pub downloaded: usize,
+    pub skipped: usize,
+    pub failed: usize,
+    pub removed: usize,
+    pub bytes_transferred: u64,
+    pub duration: Duration,
+    pub errors: Vec<FileError>,
+    /// Base URL each downloaded file came from, keyed by path.
+    pub served_by: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FileProgress {
+    pub downloaded: u64,
+    pub total_size: u64,
+    pub attempt: u32,
+    pub max_attempts: u32,
+}
+
+/// Discrete state changes pushed to subscribers alongside progress snapshots.
+#[derive(Debug, Clone)]
+pub enum DownloadEvent {
+    FileStarted {
+        path: String,
+    },
+    VerificationStarted {
+        path: String,
+    },
+    FileCompleted {
+        path: String,
+    },
+    FileFailed {
+        path: String,
+        error: String,
+    },
+    CleanupRemoved {
+        path: PathBuf,
+    },
+    /// Hashes from this run couldn't be remembered, so the next one rehashes.
+    HashIndexFailed {
+        error: String,
+    },
+    SyncFinished {
+        status: DownloadStatus,
+        error: Option<String>,
+    },
 }
 
 #[derive(Debug, Error)]
@@ -38,39 +127,333 @@ pub enum DownloadError {
     HttpError(#[from] reqwest::Error),
     #[error("I/O error: {0}")]
     IoError(#[from] std::io::Error),
-    #[error("Checksum mismatch")]
-    ChecksumMismatch,
+    #[error("Checksum mismatch (expected {expected}, got {actual})")]
+    ChecksumMismatch { expected: String, actual: String },
     #[error("Download cancelled")]
     Cancelled,
+    #[error("Download paused")]
+    Paused,
+    #[error("Not found ({status}): {url}")]
+    NotFound { status: u16, url: String },
+    #[error("Unauthorized ({status}): {url}")]
+    Unauthorized { status: u16, url: String },
+    #[error("Server error ({status}): {url}")]
+    ServerError { status: u16, url: String },
+    #[error("Rate limited ({status}) by {url}")]
+    RateLimited {
+        status: u16,
+        url: String,
+        retry_after: Option<Duration>,
+    },
+    #[error("Unexpected HTTP status ({status}): {url}")]
+    UnexpectedStatus { status: u16, url: String },
+    #[error("No data from {url} for {}s", after.as_secs_f64())]
+    Stalled { url: String, after: Duration },
+    #[error("Refusing to write {path}: {reason}")]
+    UnsafePath { path: String, reason: &'static str },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorClass {
+    Network,
+    RateLimited,
+    ServerError,
+    ClientError,
+    Io,
+    ChecksumMismatch,
+}
+
+impl DownloadError {
+    /// Machine-readable identifier for the UI, stable across message changes.
+    pub fn code(&self) -> &'static str {
+        match self {
+            DownloadError::HttpError(_) => "http_error",
+            DownloadError::IoError(_) => "io_error",
+            DownloadError::ChecksumMismatch { .. } => "checksum_mismatch",
+            DownloadError::Cancelled => "cancelled",
+            DownloadError::Paused => "paused",
+            DownloadError::NotFound { .. } => "not_found",
+            DownloadError::Unauthorized { .. } => "unauthorized",
+            DownloadError::ServerError { .. } => "server_error",
+            DownloadError::RateLimited { .. } => "rate_limited",
+            DownloadError::UnexpectedStatus { .. } => "unexpected_status",
+            DownloadError::Stalled { .. } => "stalled",
+            DownloadError::UnsafePath { .. } => "unsafe_path",
+        }
+    }
+
+    pub fn class(&self) -> Option<ErrorClass> {
+        match self {
+            DownloadError::HttpError(e) if e.is_builder() => None,
+            DownloadError::HttpError(_) | DownloadError::Stalled { .. } => {
+                Some(ErrorClass::Network)
+            }
+            DownloadError::IoError(_) => Some(ErrorClass::Io),
+            DownloadError::ChecksumMismatch { .. } => Some(ErrorClass::ChecksumMismatch),
+            DownloadError::NotFound { .. }
+            | DownloadError::Unauthorized { .. }
+            | DownloadError::UnexpectedStatus { .. } => Some(ErrorClass::ClientError),
+            DownloadError::ServerError { .. } => Some(ErrorClass::ServerError),
+            DownloadError::RateLimited { .. } => Some(ErrorClass::RateLimited),
+            DownloadError::Cancelled | DownloadError::Paused | DownloadError::UnsafePath { .. } => {
+                None
+            }
+        }
+    }
 }
 
-#[derive(Clone)]
+#[derive(Debug, Clone)]
 pub struct FileToDownload {
     pub url: String,
     pub path: String,
     pub sha256_hash: String,
+    /// Expected size from the manifest; looked up with a HEAD request when missing.
+    pub size: Option<u64>,
+    /// Base URLs to fall back to, in order, when `url` fails.
+    pub mirrors: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Total attempts per file, including the first one.
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    /// Longest delay between attempts, also capping a server's Retry-After.
+    pub max_backoff: Duration,
+    pub multiplier: f64,
+    /// Fraction of each delay that is randomised, from 0.0 to 1.0.
+    pub jitter: f64,
+    pub retry_on: HashSet<ErrorClass>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            multiplier: 2.0,
+            jitter: 0.5,
+            retry_on: HashSet::from([
+                ErrorClass::Network,
+                ErrorClass::RateLimited,
+                ErrorClass::ServerError,
+                ErrorClass::ChecksumMismatch,
+            ]),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn is_retryable(&self, error: &DownloadError) -> bool {
+        error
+            .class()
+            .is_some_and(|class| self.retry_on.contains(&class))
+    }
+
+    /// Delay before the attempt following `attempt` (1-based).
+    pub fn backoff(&self, attempt: u32, error: &DownloadError) -> Duration {
+        if let DownloadError::RateLimited {
+            retry_after: Some(retry_after),
+            ..
+        } = error
+        {
+            return (*retry_after).min(self.max_backoff);
+        }
+
+        // Worked out in f64, as growing the Duration itself would overflow
+        // after enough attempts
+        let exponent = attempt.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let seconds = self.initial_backoff.as_secs_f64() * self.multiplier.max(1.0).powi(exponent);
+        let delay = Duration::try_from_secs_f64(seconds)
+            .unwrap_or(self.max_backoff)
+            .min(self.max_backoff);
+
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        if jitter == 0.0 {
+            return delay;
+        }
+        delay.mul_f64(1.0 - rand::thread_rng().gen_range(0.0..jitter))
+    }
+}
+
+/// Outcome of comparing a manifest against what is already on disk.
+#[derive(Debug, Clone, Default)]
+pub struct SyncPlan {
+    pub up_to_date: Vec<FileToDownload>,
+    pub missing: Vec<FileToDownload>,
+    pub mismatched: Vec<FileToDownload>,
+    /// Paths relative to the destination that cleanup would remove.
+    pub extra: Vec<PathBuf>,
+    /// Entries whose path isn't safe to write, which are never downloaded.
+    pub rejected: Vec<FileError>,
+    pub up_to_date_bytes: u64,
+    pub mismatched_bytes: u64,
+    pub extra_bytes: u64,
+}
+
+impl SyncPlan {
+    pub fn files_to_download(&self) -> impl Iterator<Item = &FileToDownload> {
+        self.missing.iter().chain(self.mismatched.iter())
+    }
+}
+
+/// Exponentially smoothed transfer rate, sampled at most every half second so
+/// bursts of tiny files don't make it jump around.
+#[derive(Debug)]
+struct ThroughputMeter {
+    window_start: Instant,
+    window_bytes: u64,
+    rate: f64,
+}
+
+impl ThroughputMeter {
+    const WINDOW: Duration = Duration::from_millis(500);
+    const SMOOTHING: f64 = 0.3;
+
+    fn new() -> Self {
+        Self {
+            window_start: Instant::now(),
+            window_bytes: 0,
+            rate: 0.0,
+        }
+    }
+
+    fn record(&mut self, bytes: u64) -> f64 {
+        self.window_bytes += bytes;
+
+        let elapsed = self.window_start.elapsed();
+        if elapsed >= Self::WINDOW {
+            let sample = self.window_bytes as f64 / elapsed.as_secs_f64();
+            self.rate = if self.rate == 0.0 {
+                sample
+            } else {
+                Self::SMOOTHING * sample + (1.0 - Self::SMOOTHING) * self.rate
+            };
+            self.window_start = Instant::now();
+            self.window_bytes = 0;
+        }
+
+        self.rate
+    }
+}
+
+enum ScanResult {
+    UpToDate(u64),
+    Missing,
+    Mismatched(u64),
+}
+
+#[derive(Debug, Clone)]
+pub struct DownloadConfig {
+    /// Number of files transferred at the same time.
+    pub max_concurrent_downloads: usize,
+    pub retry_policy: RetryPolicy,
+    /// Trust hashes from the destination's index when size, mtime and inode match.
+    pub use_hash_cache: bool,
+    /// Ignore the index and hash every existing file again.
+    pub force_rehash: bool,
+    /// Limit for this job alone, on top of any limit shared between jobs.
+    pub rate_limit: RateLimit,
+    /// Base URLs tried for every file after the file's own URL and mirrors.
+    pub mirrors: Vec<String>,
+    /// Measure every mirror before downloading and try the fastest first.
+    pub probe_mirrors: bool,
+    /// Files at least this large are fetched as parallel byte ranges.
+    pub segment_threshold: Option<u64>,
+    pub max_segments: usize,
+    /// Spread the segments of one file over the available mirrors.
+    pub segment_across_mirrors: bool,
+    pub connect_timeout: Duration,
+    /// How long to wait for a server to start responding.
+    pub first_byte_timeout: Duration,
+    /// How long a transfer may go without receiving anything.
+    pub stall_timeout: Duration,
+    /// Remove unexpected files after every sync. When off, callers preview
+    /// and apply cleanup themselves.
+    pub auto_cleanup: bool,
+    pub cleanup_policy: CleanupPolicy,
+    /// Quarantine snapshots older than this are deleted after each cleanup.
+    pub quarantine_retention: Duration,
+}
+
+impl Default for DownloadConfig {
+    fn default() -> Self {
+        Self {
+            max_concurrent_downloads: 4,
+            retry_policy: RetryPolicy::default(),
+            use_hash_cache: true,
+            force_rehash: false,
+            rate_limit: RateLimit::default(),
+            mirrors: Vec::new(),
+            probe_mirrors: false,
+            segment_threshold: Some(256 * 1024 * 1024),
+            max_segments: 4,
+            segment_across_mirrors: false,
+            connect_timeout: Duration::from_secs(10),
+            first_byte_timeout: Duration::from_secs(30),
+            stall_timeout: Duration::from_secs(30),
+            auto_cleanup: true,
+            cleanup_policy: CleanupPolicy::default(),
+            quarantine_retention: Duration::from_secs(7 * 24 * 60 * 60),
+        }
+    }
 }
 
 pub struct DownloadManager {
     client: Client,
+    config: std::sync::RwLock<DownloadConfig>,
     progress: Arc<Mutex<DownloadProgress>>,
+    hash_cache: std::sync::Mutex<Option<HashCache>>,
+    events: broadcast::Sender<DownloadEvent>,
+    throughput: std::sync::Mutex<ThroughputMeter>,
+    rate_limiter: RateLimiter,
+    shared_rate_limiter: Arc<RateLimiter>,
+    mirror_health: std::sync::Mutex<MirrorHealth>,
+    mirror_probes: std::sync::Mutex<Vec<MirrorProbe>>,
+    paused: watch::Sender<bool>,
+    status_before_pause: std::sync::Mutex<Option<DownloadStatus>>,
     pub(crate) cancellation_flag: Arc<std::sync::atomic::AtomicBool>,
 }
 
+impl Default for DownloadManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl DownloadManager {
     pub fn new() -> Self {
+        Self::with_config(DownloadConfig::default())
+    }
+
+    pub fn with_config(config: DownloadConfig) -> Self {
+        Self::with_rate_limiter(config, Arc::new(RateLimiter::new(RateLimit::default())))
+    }
+
+    /// Like `with_config`, with transfers also paced by `shared_rate_limiter`
+    /// alongside every other manager holding it.
+    pub fn with_rate_limiter(
+        config: DownloadConfig,
+        shared_rate_limiter: Arc<RateLimiter>,
+    ) -> Self {
         Self {
-            client: Client::new(),
-            progress: Arc::new(Mutex::new(DownloadProgress {
-                status: DownloadStatus::Ready,
-                files_total: 0,
-                files_total_completed: 0,
-                verification_total_completed: 0,
-                current_file_downloaded: 0,
-                current_file_total_size: 0,
-                current_file_path: String::new(),
-                failed_files: HashMap::new(),
-            })),
+            // Falls back to default settings only if the TLS backend can't initialise
+            client: Client::builder()
+                .connect_timeout(config.connect_timeout)
+                .build()
+                .unwrap_or_default(),
+            rate_limiter: RateLimiter::new(config.rate_limit.clone()),
+            shared_rate_limiter,
+            mirror_health: std::sync::Mutex::new(MirrorHealth::default()),
+            mirror_probes: std::sync::Mutex::new(Vec::new()),
+            config: std::sync::RwLock::new(config),
+            progress: Arc::new(Mutex::new(DownloadProgress::default())),
+            hash_cache: std::sync::Mutex::new(None),
+            events: broadcast::channel(1024).0,
+            throughput: std::sync::Mutex::new(ThroughputMeter::new()),
+            paused: watch::channel(false).0,
+            status_before_pause: std::sync::Mutex::new(None),
             cancellation_flag: Arc::new(std::sync::atomic::AtomicBool::new(false)),
         }
     }
@@ -79,238 +462,1182 @@ impl DownloadManager {
         &self,
         destination_folder: impl AsRef<Path>,
         files: Vec<FileToDownload>,
-    ) -> Result<(), DownloadError> {
-        let destination_folder = destination_folder.as_ref().to_path_buf();
+    ) -> Result<SyncSummary, DownloadError> {
+        let expected_files = expected_files(&files);
+        let result = self.run_download(destination_folder.as_ref(), files).await;
+
+        // A pause that came too late to take effect ends with the run
+        self.clear_pause();
+        let failed = matches!(&result, Err(e) if !matches!(e, DownloadError::Cancelled));
+        if failed {
+            // Whatever was hashed before the failure is still worth keeping
+            self.save_hash_cache(&expected_files).await;
+        }
+        let status = {
+            let mut progress = self.progress.lock().await;
+            if failed {
+                progress.status = DownloadStatus::Error;
+            }
+            progress.status
+        };
+        self.emit(DownloadEvent::SyncFinished {
+            status,
+            error: result.as_ref().err().map(|e| e.to_string()),
+        });
+
+        result
+    }
+
+    async fn run_download(
+        &self,
+        destination_folder: &Path,
+        files: Vec<FileToDownload>,
+    ) -> Result<SyncSummary, DownloadError> {
+        let started = Instant::now();
+        let destination_folder = destination_folder.to_path_buf();
         let num_files = files.len();
 
         self.cancellation_flag
             .store(false, std::sync::atomic::Ordering::SeqCst);
+        self.clear_pause();
 
         self.initialize_progress(num_files).await;
+        *self.mirror_health.lock().unwrap() = MirrorHealth::default();
 
-        let mut expected_files = HashSet::new();
+        let expected_files = expected_files(&files);
 
-        for (_, file) in files.iter().enumerate() {
-            if self.is_cancelled() {
-                self.reset_progress().await;
-                return Err(DownloadError::Cancelled);
-            }
+        let mut plan = self.scan(&destination_folder, &files).await?;
+        self.resolve_sizes(&mut plan).await;
+        for error in &plan.rejected {
+            self.update_progress_for_rejected_file(error).await;
+        }
 
-            self.update_progress_for_file(file).await;
+        let config = self.config();
+        let to_download: Vec<FileToDownload> = plan.files_to_download().cloned().collect();
+        if config.probe_mirrors && probe_targets(&to_download, &config.mirrors).len() > 1 {
+            self.probe_mirrors(&to_download).await;
+        }
+        self.update_progress_for_up_to_date_files(plan.up_to_date.len())
+            .await;
+        self.initialize_byte_progress(&plan).await;
 
-            let formatted_file_path = PathBuf::from(file.path.trim_start_matches('/'));
-            expected_files.insert(PathBuf::from(&formatted_file_path));
-            let file_path = destination_folder.join(&formatted_file_path);
+        let concurrency = self.config().max_concurrent_downloads.max(1);
 
-            if self.file_is_valid(&file_path, &file.sha256_hash).await {
-                self.update_progress_for_completed_file().await;
-                continue;
-            }
+        stream::iter(plan.files_to_download())
+            .for_each_concurrent(concurrency, |file| {
+                self.process_file(&destination_folder, file)
+            })
+            .await;
 
-            match self.download_file(file, &file_path).await {
-                Ok(_) => self.update_progress_for_completed_file().await,
-                Err(e) => {
-                    self.update_progress_for_failed_file(file, &e.to_string()).await;
-                    // Continue with the next file instead of returning early
-                    continue;
-                }
-            }
+        if self.is_cancelled() {
+            self.reset_progress().await;
+            return Err(DownloadError::Cancelled);
         }
 
-        self.cleanup_files(&destination_folder, &expected_files)
-            .await?;
+        let removed = if self.config().auto_cleanup {
+            self.cleanup_files(&destination_folder, &expected_files)
+                .await?
+        } else {
+            Vec::new()
+        };
 
+        self.save_hash_cache(&expected_files).await;
         self.finalize_progress().await;
 
-        Ok(())
+        let progress = self.get_progress().await;
+        let mut errors: Vec<FileError> = progress.failed_files.into_values().collect();
+        errors.sort_by(|a, b| a.path.cmp(&b.path));
+
+        Ok(SyncSummary {
+            downloaded: plan.files_to_download().count() + plan.rejected.len() - errors.len(),
+            skipped: plan.up_to_date.len(),
+            failed: errors.len(),
+            removed: removed.len(),
+            bytes_transferred: progress.bytes_transferred,
+            duration: started.elapsed(),
+            errors,
+            served_by: progress.served_by,
+        })
     }
 
-    async fn download_file(
+    /// Computes a sync plan without downloading or removing anything.
+    pub async fn plan(
         &self,
-        file: &FileToDownload,
-        file_path: &Path,
-    ) -> Result<(), DownloadError> {
-        self.prepare_for_download().await;
+        destination_folder: impl AsRef<Path>,
+        files: &[FileToDownload],
+    ) -> Result<SyncPlan, DownloadError> {
+        self.cancellation_flag
+            .store(false, std::sync::atomic::Ordering::SeqCst);
+        self.clear_pause();
 
-        let response = self.client.get(&file.url).send().await?;
-        let total_size = response.content_length().unwrap_or(0);
+        let destination_folder = destination_folder.as_ref();
+        self.initialize_progress(files.len()).await;
+        let plan = self.scan(destination_folder, files).await;
+        self.clear_pause();
+        self.progress.lock().await.status = DownloadStatus::Ready;
 
-        if let Some(parent) = file_path.parent() {
-            fs::create_dir_all(parent)?;
+        // Planning never creates the destination, and there is nothing to index without it
+        if destination_folder.exists() {
+            self.save_hash_cache(&expected_files(files)).await;
         }
 
-        let mut file_handle = File::create(file_path)?;
-        let mut downloaded = 0;
-        let mut stream = response.bytes_stream();
+        plan
+    }
 
-        while let Some(chunk) = stream.next().await {
-            if self.is_cancelled() {
-                self.reset_progress().await;
-                return Err(DownloadError::Cancelled);
+    /// Hashes every existing file across all cores and sorts the manifest
+    /// into up to date, missing and mismatched entries.
+    async fn scan(
+        &self,
+        destination_folder: &Path,
+        files: &[FileToDownload],
+    ) -> Result<SyncPlan, DownloadError> {
+        let mut plan = SyncPlan::default();
+        let mut safe_files = Vec::new();
+        for file in files {
+            match relative_path(&file.path) {
+                Ok(_) => safe_files.push(file),
+                Err(e) => plan.rejected.push(FileError {
+                    path: file.path.clone(),
+                    code: e.code(),
+                    message: e.to_string(),
+                }),
             }
+        }
 
-            let chunk = chunk?;
-            file_handle.write_all(&chunk)?;
-            downloaded += chunk.len() as u64;
+        self.initialize_scan_progress(safe_files.len()).await;
+        self.load_hash_cache(destination_folder).await;
+
+        let concurrency = std::thread::available_parallelism().map_or(4, |n| n.get());
+
+        // Collected up front so the stream holds futures rather than a borrowing closure
+        let scans: Vec<_> = safe_files
+            .into_iter()
+            .map(|file| self.scan_file(destination_folder, file))
+            .collect();
+        let results: Vec<(&FileToDownload, ScanResult)> = stream::iter(scans)
+            .buffer_unordered(concurrency)
+            .collect()
+            .await;
+
+        if self.is_cancelled() {
+            self.reset_progress().await;
+            return Err(DownloadError::Cancelled);
+        }
+
+        for (file, result) in results {
+            match result {
+                ScanResult::UpToDate(size) => {
+                    plan.up_to_date_bytes += size;
+                    plan.up_to_date.push(file.clone());
+                }
+                ScanResult::Missing => plan.missing.push(file.clone()),
+                ScanResult::Mismatched(size) => {
+                    plan.mismatched_bytes += size;
+                    plan.mismatched.push(file.clone());
+                }
+            }
+        }
+
+        let expected_files = expected_files(files);
+        let destination = destination_folder.to_path_buf();
+        let extra = run_blocking(move || preview_unexpected(&destination, &expected_files)).await?;
 
-            self.update_download_progress(downloaded, total_size).await;
+        for entry in extra.into_iter().filter(|entry| !entry.is_dir) {
+            plan.extra_bytes += entry.size;
+            plan.extra.push(entry.path);
         }
 
-        self.verify_file(file_path, &file.sha256_hash).await
+        Ok(plan)
+    }
+
+    async fn scan_file<'a>(
+        &self,
+        destination_folder: &Path,
+        file: &'a FileToDownload,
+    ) -> (&'a FileToDownload, ScanResult) {
+        // Only called for files that passed validation in scan
+        let relative_path = relative_path(&file.path).unwrap_or_default();
+        let file_path = destination_folder.join(&relative_path);
+        let result = self
+            .check_existing_file(&relative_path, &file_path, &file.sha256_hash)
+            .await;
+        (file, result)
     }
 
-    async fn verify_file(
+    async fn check_existing_file(
         &self,
+        relative_path: &Path,
         file_path: &Path,
         expected_hash: &str,
-    ) -> Result<(), DownloadError> {
-        let calculated_hash = self.calculate_sha256(file_path).await?;
-        if calculated_hash != expected_hash {
-            return Err(DownloadError::ChecksumMismatch);
+    ) -> ScanResult {
+        if self.wait_while_paused().await.is_err() {
+            return ScanResult::Missing;
         }
 
-        let mut progress = self.progress.lock().await;
-        progress.verification_total_completed += 1;
-        
-        Ok(())
+        let metadata = match tokio::fs::metadata(file_path).await {
+            Ok(metadata) if metadata.is_file() => metadata,
+            _ => {
+                self.update_scan_progress(0).await;
+                return ScanResult::Missing;
+            }
+        };
+        let size = metadata.len();
+        let fingerprint = FileFingerprint::from_metadata(&metadata);
+
+        // Unreadable files are treated as mismatched and downloaded again
+        let existing_hash = match self.cached_hash(relative_path, &fingerprint) {
+            Some(hash) => Ok(hash),
+            None => {
+                let hash = self.calculate_sha256(file_path).await;
+                if let Ok(hash) = &hash {
+                    self.cache_hash(relative_path, fingerprint, hash);
+                }
+                hash
+            }
+        };
+        self.update_scan_progress(size).await;
+
+        match existing_hash {
+            Ok(hash) if hash == expected_hash => ScanResult::UpToDate(size),
+            _ => ScanResult::Mismatched(size),
+        }
     }
 
-    pub async fn cleanup_files(
-        &self,
-        destination_folder: &Path,
-        expected_files: &HashSet<PathBuf>,
-    ) -> std::io::Result<()> {
-        let base_path = PathBuf::from(destination_folder);
+    /// Samples every mirror the files can come from. Later downloads on this
+    /// manager try mirrors in the measured order.
+    pub async fn probe_mirrors(&self, files: &[FileToDownload]) -> Vec<MirrorProbe> {
+        let targets = probe_targets(files, &self.config().mirrors);
 
-        // Extract managed directories
-        let managed_dirs: HashSet<PathBuf> = expected_files
+        let probes: Vec<_> = targets
             .iter()
-            .filter_map(|path| path.components().next())
-            .map(|comp| base_path.join(comp.as_os_str()))
+            .map(|target| probe(&self.client, target))
             .collect();
+        let probes: Vec<MirrorProbe> = stream::iter(probes)
+            .buffered(targets.len().max(1))
+            .collect()
+            .await;
 
-        // Collect all paths that should be kept
-        let mut keep_paths = HashSet::new();
-        for expected_file in expected_files {
-            let full_path = base_path.join(expected_file);
-            keep_paths.insert(full_path.clone());
-            // Add all parent directories to keep_paths
-            for ancestor in full_path.ancestors().skip(1) {
-                if ancestor.starts_with(&base_path) {
-                    keep_paths.insert(ancestor.to_path_buf());
-                } else {
-                    break;
+        *self.mirror_probes.lock().unwrap() = probes.clone();
+        probes
+    }
+
+    pub fn mirror_probes(&self) -> Vec<MirrorProbe> {
+        self.mirror_probes.lock().unwrap().clone()
+    }
+
+    /// Fills in sizes the manifest didn't provide with HEAD requests, so the
+    /// byte totals cover the whole job. Unknown sizes are left as `None`.
+    async fn resolve_sizes(&self, plan: &mut SyncPlan) {
+        let concurrency = self.config().max_concurrent_downloads.max(1);
+
+        let lookups: Vec<_> = plan
+            .missing
+            .iter_mut()
+            .chain(plan.mismatched.iter_mut())
+            .filter(|file| file.size.is_none())
+            .map(|file| async move {
+                if let Ok(response) = self.send(self.client.head(&file.url), &file.url).await {
+                    if response.status().is_success() {
+                        // content_length() describes the empty HEAD body, not the file
+                        file.size = response
+                            .headers()
+                            .get(CONTENT_LENGTH)
+                            .and_then(|v| v.to_str().ok())
+                            .and_then(|v| v.parse().ok());
+                    }
                 }
-            }
+            })
+            .collect();
+
+        stream::iter(lookups)
+            .buffer_unordered(concurrency)
+            .collect::<Vec<()>>()
+            .await;
+    }
+
+    async fn process_file(&self, destination_folder: &Path, file: &FileToDownload) {
+        if self.wait_while_paused().await.is_err() {
+            return;
         }
 
-        // Walk the directory tree in reverse order (bottom-up)
-        for entry in WalkDir::new(destination_folder).contents_first(true) {
-            let entry = entry?;
-            let path = entry.path().to_path_buf();
+        self.update_progress_for_file(file).await;
 
-            // Only process files and directories within managed directories
-            if !managed_dirs.iter().any(|dir| path.starts_with(dir)) {
-                continue;
+        let file_path = match self.writable_path(destination_folder, file).await {
+            Ok(file_path) => file_path,
+            Err(e) => return self.update_progress_for_failed_file(file, &e).await,
+        };
+
+        let config = self.config();
+        let retry_policy = config.retry_policy;
+        let max_attempts = retry_policy.max_attempts.max(1);
+        let mut attempt = 1;
+
+        let mut sources = sources_for(file, &config.mirrors);
+        let ranking = rank(&self.mirror_probes.lock().unwrap());
+        apply_ranking(&mut sources, &ranking);
+        // Sources that failed this file in the current attempt
+        let mut tried = Vec::new();
+
+        loop {
+            if self.wait_while_paused().await.is_err() {
+                return self.clear_active_file(file).await;
+            }
+            self.update_attempt(file, attempt, max_attempts).await;
+
+            let choice = self.mirror_health.lock().unwrap().choose(&sources, &tried);
+            let (index, source) = match choice {
+                Some(choice) => choice,
+                None => return self.clear_active_file(file).await,
+            };
+
+            // Other mirrors this attempt may spread segments over
+            let mut urls = vec![source.url.clone()];
+            if config.segment_across_mirrors {
+                let health = self.mirror_health.lock().unwrap();
+                urls.extend(
+                    sources
+                        .iter()
+                        .enumerate()
+                        .filter(|(i, other)| {
+                            *i != index && !tried.contains(i) && !health.is_blacklisted(&other.base)
+                        })
+                        .map(|(_, other)| other.url.clone()),
+                );
             }
 
-            if !keep_paths.contains(&path) {
-                if entry.file_type().is_dir() {
-                    // Check if directory is empty before removing
-                    if fs::read_dir(&path)?.next().is_none() {
-                        println!("Removing empty directory: {:?}", path);
-                        fs::remove_dir(path)?;
+            match self.download_file(file, &file_path, &urls).await {
+                Ok(_) => {
+                    self.mirror_health
+                        .lock()
+                        .unwrap()
+                        .record_success(&source.base);
+                    return self.update_progress_for_completed_file(file, source).await;
+                }
+                Err(DownloadError::Cancelled) => return self.clear_active_file(file).await,
+                // The partial file is the checkpoint, so resuming doesn't use up an attempt
+                Err(DownloadError::Paused) => continue,
+                Err(e) => {
+                    self.mirror_health
+                        .lock()
+                        .unwrap()
+                        .record_failure(&source.base);
+                    tried.push(index);
+
+                    // Moving on to another mirror doesn't need a backoff
+                    if tried.len() < sources.len() {
+                        continue;
                     }
-                } else {
-                    println!("Removing file: {:?}", path);
-                    fs::remove_file(path)?;
+                    if attempt >= max_attempts || !retry_policy.is_retryable(&e) {
+                        return self.update_progress_for_failed_file(file, &e).await;
+                    }
+
+                    let delay = retry_policy.backoff(attempt, &e);
+                    if self.sleep_unless_cancelled(delay).await.is_err() {
+                        return self.clear_active_file(file).await;
+                    }
+                    tried.clear();
+                    attempt += 1;
                 }
             }
         }
-
-        Ok(())
     }
 
-    async fn reset_progress(&self) {
-        let mut progress = self.progress.lock().await;
-        progress.status = DownloadStatus::Ready;
-        progress.files_total = 0;
-        progress.files_total_completed = 0;
-        progress.verification_total_completed = 0;
-        progress.current_file_downloaded = 0;
-        progress.current_file_total_size = 0;
-        progress.current_file_path = String::new();
-        progress.failed_files.clear();
-    }
+    /// Where `file` goes under the destination, checked so that neither its
+    /// path nor a symlink along the way leads outside the destination.
+    async fn writable_path(
+        &self,
+        destination_folder: &Path,
+        file: &FileToDownload,
+    ) -> Result<PathBuf, DownloadError> {
+        let file_path = destination_folder.join(relative_path(&file.path)?);
 
-    async fn initialize_progress(&self, num_files: usize) {
-        let mut progress = self.progress.lock().await;
-        progress.status = DownloadStatus::Initiating;
-        progress.files_total = num_files;
-        progress.files_total_completed = 0;
-        progress.verification_total_completed = 0;
-        progress.current_file_downloaded = 0;
-        progress.current_file_total_size = 0;
-        progress.current_file_path = String::new();
-        progress.failed_files.clear();
-    }
+        let destination = destination_folder.to_path_buf();
+        let checked = file_path.clone();
+        let contained = run_blocking(move || {
+            // The destination is about to be written to anyway, and only an
+            // existing folder can be canonicalized
+            fs::create_dir_all(&destination)?;
+            let root = destination.canonicalize()?;
+            Ok([
+                partial_path(&checked),
+                validator_path(&checked),
+                segments_path(&checked),
+                checked,
+            ]
+            .iter()
+            .all(|path| stays_within(&root, path)))
+        })
+        .await?;
 
-    async fn update_progress_for_file(&self, file: &FileToDownload) {
-        let mut progress = self.progress.lock().await;
-        progress.status = DownloadStatus::Downloading;
-        progress.current_file_path = file.path.clone();
+        if !contained {
+            return Err(DownloadError::UnsafePath {
+                path: file.path.clone(),
+                reason: "a symlink leads outside the destination",
+            });
+        }
+        Ok(file_path)
     }
 
-    async fn update_progress_for_completed_file(&self) {
-        let mut progress = self.progress.lock().await;
-        progress.files_total_completed += 1;
-        progress.verification_total_completed += 1;
-        progress.current_file_downloaded = 0;
-        progress.current_file_total_size = 0;
-    }
+    /// Fetches `file` from `urls[0]`, or in segments spread over `urls` when
+    /// it is large enough and the server accepts ranges.
+    async fn download_file(
+        &self,
+        file: &FileToDownload,
+        file_path: &Path,
+        urls: &[String],
+    ) -> Result<(), DownloadError> {
+        self.prepare_for_download(file).await;
 
-    async fn update_progress_for_failed_file(&self, file: &FileToDownload, error: &str) {
-        let mut progress = self.progress.lock().await;
-        progress.failed_files.insert(file.path.clone(), error.to_string());
-    }
+        if let Some(parent) = file_path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
 
-    async fn finalize_progress(&self) {
-        let mut progress = self.progress.lock().await;
-        progress.status = if progress.failed_files.is_empty() {
-            DownloadStatus::Done
-        } else {
-            DownloadStatus::Error
-        };
-        progress.verification_total_completed = progress.files_total;
-    }
+        let url = urls[0].as_str();
+        let partial_path = partial_path(file_path);
+        let validator_path = validator_path(file_path);
+        let segments_path = segments_path(file_path);
 
-    async fn file_is_valid(&self, file_path: &Path, expected_hash: &str) -> bool {
-        if let Ok(existing_hash) = self.calculate_sha256(file_path).await {
-            existing_hash == expected_hash
-        } else {
-            false
+        if let Some(size) = self.segmented_size(file, file_path, url).await {
+            return self.download_segmented(file, file_path, urls, size).await;
+        }
+        if tokio::fs::metadata(&segments_path).await.is_ok() {
+            // A preallocated segmented file can't be resumed as a single stream
+            remove_if_exists(&partial_path).await?;
+            remove_if_exists(&segments_path).await?;
         }
-    }
 
-    async fn prepare_for_download(&self) {
-        let mut progress = self.progress.lock().await;
-        progress.status = DownloadStatus::Downloading;
-        progress.current_file_downloaded = 0;
-        progress.current_file_total_size = 0;
-    }
+        let resume_from = tokio::fs::metadata(&partial_path)
+            .await
+            .map(|m| m.len())
+            .unwrap_or(0);
+        let validator = tokio::fs::read_to_string(&validator_path).await.ok();
 
-    async fn update_download_progress(&self, downloaded: u64, total_size: u64) {
+        let mut response = self
+            .request_file(url, resume_from, validator.as_deref())
+            .await?;
+
+        let mut resumed = false;
+        if resume_from > 0 {
+            match response.status() {
+                StatusCode::PARTIAL_CONTENT
+                    if content_range_start(response.headers()) == Some(resume_from) =>
+                {
+                    resumed = true
+                }
+                StatusCode::PARTIAL_CONTENT | StatusCode::RANGE_NOT_SATISFIABLE => {
+                    // The server rejected the range, so start over with a plain request
+                    response = self.request_file(url, 0, None).await?;
+                }
+                _ => {}
+            }
+        }
+
+        // Checked before touching the disk so an error page never lands in the file
+        check_status(&response)?;
+
+        let (mut hasher, mut file_handle, mut downloaded) = if resumed {
+            // Only the bytes from the previous attempt are read back
+            let prefix_path = partial_path.clone();
+            let hasher = run_blocking(move || {
+                let mut hasher = Sha256::new();
+                std::io::copy(&mut File::open(prefix_path)?, &mut hasher)?;
+                Ok(hasher)
+            })
+            .await?;
+            let handle = OpenOptions::new().append(true).open(&partial_path).await?;
+            (hasher, handle, resume_from)
+        } else {
+            match response_validator(response.headers()) {
+                Some(validator) => tokio::fs::write(&validator_path, validator).await?,
+                None => remove_if_exists(&validator_path).await?,
+            }
+            let handle = tokio::fs::File::create(&partial_path).await?;
+            (Sha256::new(), handle, 0)
+        };
+
+        let total_size = downloaded + response.content_length().unwrap_or(0);
+        let mut stream = response.bytes_stream();
+
+        while let Some(chunk) = self.next_chunk(&mut stream, url).await? {
+            if self.is_cancelled() {
+                return Err(DownloadError::Cancelled);
+            }
+            if self.is_paused() {
+                // Drop the connection and keep what we have, resume picks it up with a range request
+                file_handle.flush().await?;
+                return Err(DownloadError::Paused);
+            }
+
+            file_handle.write_all(&chunk).await?;
+            hasher.update(&chunk);
+            downloaded += chunk.len() as u64;
+
+            self.update_download_progress(file, downloaded, total_size, chunk.len() as u64)
+                .await;
+            self.pace(chunk.len() as u64).await?;
+        }
+
+        // Make sure the bytes are on disk before the rename can expose them
+        file_handle.flush().await?;
+        file_handle.sync_all().await?;
+        drop(file_handle);
+
+        self.emit(DownloadEvent::VerificationStarted {
+            path: file.path.clone(),
+        });
+
+        let actual_hash = to_hex(&hasher.finalize());
+        self.finish_download(file, file_path, &actual_hash).await
+    }
+
+    /// Size to fetch in segments, or `None` when the file should be fetched as
+    /// a single stream.
+    async fn segmented_size(
+        &self,
+        file: &FileToDownload,
+        file_path: &Path,
+        url: &str,
+    ) -> Option<u64> {
+        let config = self.config();
+        let size = file.size?;
+        if config.max_segments < 2 || config.segment_threshold.is_none_or(|t| size < t) {
+            return None;
+        }
+
+        // A single-stream partial file carries on the way it was started
+        let partial_exists = tokio::fs::metadata(partial_path(file_path)).await.is_ok();
+        let segmented = tokio::fs::metadata(segments_path(file_path)).await.is_ok();
+        if partial_exists && !segmented {
+            return None;
+        }
+
+        let response = self.send(self.client.head(url), url).await.ok()?;
+        let headers = response.headers();
+        let accepts_ranges = headers
+            .get(ACCEPT_RANGES)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.trim().eq_ignore_ascii_case("bytes"));
+        let length: Option<u64> = headers
+            .get(CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok());
+
+        (response.status().is_success() && accepts_ranges && length == Some(size)).then_some(size)
+    }
+
+    /// Fetches the file as concurrent byte ranges written into a preallocated
+    /// partial file. Progress per segment is kept beside it so an interrupted
+    /// transfer resumes each range where it stopped.
+    async fn download_segmented(
+        &self,
+        file: &FileToDownload,
+        file_path: &Path,
+        urls: &[String],
+        size: u64,
+    ) -> Result<(), DownloadError> {
+        let partial_path = partial_path(file_path);
+        let segments_path = segments_path(file_path);
+
+        let saved = tokio::fs::read_to_string(&segments_path)
+            .await
+            .ok()
+            .and_then(|contents| segments::parse(&contents, size));
+        let partial_size = tokio::fs::metadata(&partial_path).await.map(|m| m.len());
+        let segments = match saved {
+            Some(segments) if partial_size.is_ok_and(|len| len == size) => segments,
+            _ => {
+                let handle = tokio::fs::File::create(&partial_path).await?;
+                handle.set_len(size).await?;
+                let segments = segments::split(size, self.config().max_segments);
+                tokio::fs::write(&segments_path, segments::format(size, &segments)).await?;
+                segments
+            }
+        };
+        remove_if_exists(&validator_path(file_path)).await?;
+
+        let resumed: u64 = segments.iter().map(|segment| segment.done).sum();
+        let downloaded = Mutex::new(resumed);
+        self.update_download_progress(file, resumed, size, 0).await;
+
+        let done: Vec<AtomicU64> = segments
+            .iter()
+            .map(|segment| AtomicU64::new(segment.done))
+            .collect();
+        let fetches: Vec<_> = segments
+            .iter()
+            .enumerate()
+            .filter(|(_, segment)| !segment.is_complete())
+            .map(|(i, segment)| {
+                let url = &urls[i % urls.len()];
+                self.fetch_segment(
+                    file,
+                    &partial_path,
+                    url,
+                    segment,
+                    &done[i],
+                    &downloaded,
+                    size,
+                )
+            })
+            .collect();
+        let result = futures::future::try_join_all(fetches).await;
+
+        // Persist the data before recording how far each segment got
+        OpenOptions::new()
+            .write(true)
+            .open(&partial_path)
+            .await?
+            .sync_all()
+            .await?;
+        let progress: Vec<Segment> = segments
+            .iter()
+            .zip(&done)
+            .map(|(segment, done)| Segment {
+                done: done.load(Ordering::SeqCst),
+                ..*segment
+            })
+            .collect();
+        tokio::fs::write(&segments_path, segments::format(size, &progress)).await?;
+        result?;
+
+        if !progress.iter().all(Segment::is_complete) {
+            return Err(DownloadError::IoError(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "server sent less than the requested range",
+            )));
+        }
+
+        self.emit(DownloadEvent::VerificationStarted {
+            path: file.path.clone(),
+        });
+
+        // Segments arrive out of order, so the hash is taken over the finished file
+        let actual_hash = self.calculate_sha256(&partial_path).await?;
+        self.finish_download(file, file_path, &actual_hash).await
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn fetch_segment(
+        &self,
+        file: &FileToDownload,
+        partial_path: &Path,
+        url: &str,
+        segment: &Segment,
+        done: &AtomicU64,
+        downloaded: &Mutex<u64>,
+        size: u64,
+    ) -> Result<(), DownloadError> {
+        let start = segment.start + done.load(Ordering::SeqCst);
+        let request = self
+            .client
+            .get(url)
+            .header(RANGE, format!("bytes={}-{}", start, segment.end - 1));
+        let response = self.send(request, url).await?;
+        check_status(&response)?;
+        if response.status() != StatusCode::PARTIAL_CONTENT
+            || content_range_start(response.headers()) != Some(start)
+        {
+            return Err(DownloadError::UnexpectedStatus {
+                status: response.status().as_u16(),
+                url: url.to_string(),
+            });
+        }
+
+        let mut handle = OpenOptions::new().write(true).open(partial_path).await?;
+        handle.seek(SeekFrom::Start(start)).await?;
+        let mut stream = response.bytes_stream();
+
+        while let Some(chunk) = self.next_chunk(&mut stream, url).await? {
+            if self.is_cancelled() {
+                return Err(DownloadError::Cancelled);
+            }
+            if self.is_paused() {
+                handle.flush().await?;
+                return Err(DownloadError::Paused);
+            }
+
+            // Never write into the next segment, whatever the server sends
+            let remaining = segment.size() - done.load(Ordering::SeqCst);
+            let chunk = &chunk[..chunk.len().min(remaining as usize)];
+            handle.write_all(chunk).await?;
+            done.fetch_add(chunk.len() as u64, Ordering::SeqCst);
+
+            {
+                // Held while reporting so the file's total never goes backwards
+                let mut downloaded = downloaded.lock().await;
+                *downloaded += chunk.len() as u64;
+                self.update_download_progress(file, *downloaded, size, chunk.len() as u64)
+                    .await;
+            }
+            self.pace(chunk.len() as u64).await?;
+
+            if done.load(Ordering::SeqCst) >= segment.size() {
+                break;
+            }
+        }
+
+        handle.flush().await?;
+        Ok(())
+    }
+
+    /// Waits as long as the rate limits require after receiving `bytes`.
+    async fn pace(&self, bytes: u64) -> Result<(), DownloadError> {
+        let wait = self
+            .rate_limiter
+            .reserve(bytes)
+            .max(self.shared_rate_limiter.reserve(bytes));
+        self.sleep_unless_cancelled(wait).await
+    }
+
+    /// Verifies a complete partial file and moves it into place.
+    async fn finish_download(
+        &self,
+        file: &FileToDownload,
+        file_path: &Path,
+        actual_hash: &str,
+    ) -> Result<(), DownloadError> {
+        let partial_path = partial_path(file_path);
+        let validator_path = validator_path(file_path);
+        let segments_path = segments_path(file_path);
+
+        if let Err(e) = self.verify_digest(&file.sha256_hash, actual_hash).await {
+            // A corrupt partial file would otherwise be resumed forever
+            remove_if_exists(&partial_path).await?;
+            remove_if_exists(&validator_path).await?;
+            remove_if_exists(&segments_path).await?;
+            return Err(e);
+        }
+
+        let target_path = file_path.to_path_buf();
+        run_blocking(move || replace_file(&partial_path, &target_path)).await?;
+        remove_if_exists(&validator_path).await?;
+        remove_if_exists(&segments_path).await?;
+
+        let metadata = tokio::fs::metadata(file_path).await?;
+        self.cache_hash(
+            &relative_path(&file.path)?,
+            FileFingerprint::from_metadata(&metadata),
+            actual_hash,
+        );
+
+        Ok(())
+    }
+
+    async fn request_file(
+        &self,
+        url: &str,
+        resume_from: u64,
+        validator: Option<&str>,
+    ) -> Result<Response, DownloadError> {
+        let mut request = self.client.get(url);
+
+        if resume_from > 0 {
+            request = request.header(RANGE, format!("bytes={}-", resume_from));
+            if let Some(validator) = validator {
+                request = request.header(IF_RANGE, validator);
+            }
+        }
+
+        self.send(request, url).await
+    }
+
+    /// Sends `request`, failing as stalled if the server doesn't answer in time.
+    async fn send(&self, request: RequestBuilder, url: &str) -> Result<Response, DownloadError> {
+        let timeout = self.config.read().unwrap().first_byte_timeout;
+        match tokio::time::timeout(timeout, request.send()).await {
+            Ok(response) => Ok(response?),
+            Err(_) => Err(DownloadError::Stalled {
+                url: url.to_string(),
+                after: timeout,
+            }),
+        }
+    }
+
+    /// The next chunk of a response body, failing as stalled if nothing
+    /// arrives in time. The connection is dropped with the stream.
+    async fn next_chunk<S, T>(&self, stream: &mut S, url: &str) -> Result<Option<T>, DownloadError>
+    where
+        S: Stream<Item = reqwest::Result<T>> + Unpin,
+    {
+        let timeout = self.config.read().unwrap().stall_timeout;
+        match tokio::time::timeout(timeout, stream.next()).await {
+            Ok(chunk) => Ok(chunk.transpose()?),
+            Err(_) => Err(DownloadError::Stalled {
+                url: url.to_string(),
+                after: timeout,
+            }),
+        }
+    }
+
+    async fn verify_digest(
+        &self,
+        expected_hash: &str,
+        actual_hash: &str,
+    ) -> Result<(), DownloadError> {
+        if actual_hash != expected_hash {
+            return Err(DownloadError::ChecksumMismatch {
+                expected: expected_hash.to_string(),
+                actual: actual_hash.to_string(),
+            });
+        }
+
+        let mut progress = self.progress.lock().await;
+        progress.verification_total_completed += 1;
+
+        Ok(())
+    }
+
+    /// What `cleanup_files` would remove, without touching anything.
+    pub async fn preview_cleanup(
+        &self,
+        destination_folder: &Path,
+        expected_files: &HashSet<PathBuf>,
+    ) -> std::io::Result<Vec<CleanupEntry>> {
+        let destination_folder = destination_folder.to_path_buf();
+        let expected_files = expected_files.clone();
+        run_blocking(move || preview_unexpected(&destination_folder, &expected_files)).await
+    }
+
+    pub async fn cleanup_files(
+        &self,
+        destination_folder: &Path,
+        expected_files: &HashSet<PathBuf>,
+    ) -> std::io::Result<Vec<PathBuf>> {
+        self.remove_unexpected(destination_folder, expected_files, None)
+            .await
+    }
+
+    /// Removes only the previewed paths in `approved`. Anything that is no
+    /// longer unexpected by now is left alone.
+    pub async fn apply_cleanup(
+        &self,
+        destination_folder: &Path,
+        expected_files: &HashSet<PathBuf>,
+        approved: &[PathBuf],
+    ) -> std::io::Result<Vec<PathBuf>> {
+        let approved = approved.iter().cloned().collect();
+        self.remove_unexpected(destination_folder, expected_files, Some(approved))
+            .await
+    }
+
+    async fn remove_unexpected(
+        &self,
+        destination_folder: &Path,
+        expected_files: &HashSet<PathBuf>,
+        approved: Option<HashSet<PathBuf>>,
+    ) -> std::io::Result<Vec<PathBuf>> {
+        let config = self.config();
+        let destination_folder = destination_folder.to_path_buf();
+        let expected_files = expected_files.clone();
+
+        let removed = run_blocking(move || {
+            let removed = remove_unexpected_files(
+                &destination_folder,
+                &expected_files,
+                approved.as_ref(),
+                config.cleanup_policy,
+            )?;
+            cleanup::expire(&destination_folder, config.quarantine_retention)?;
+            Ok(removed)
+        })
+        .await?;
+
+        for path in &removed {
+            self.emit(DownloadEvent::CleanupRemoved { path: path.clone() });
+        }
+
+        Ok(removed)
+    }
+
+    async fn reset_progress(&self) {
+        let mut progress = self.progress.lock().await;
+        progress.status = DownloadStatus::Ready;
+        progress.files_total = 0;
+        progress.files_total_completed = 0;
+        progress.verification_total_completed = 0;
+        progress.current_file_downloaded = 0;
+        progress.current_file_total_size = 0;
+        progress.current_file_path = String::new();
+        progress.failed_files.clear();
+        progress.active_files.clear();
+        progress.served_by.clear();
+        progress.scan_files_total = 0;
+        progress.scan_files_completed = 0;
+        progress.scan_bytes_completed = 0;
+        progress.bytes_transferred = 0;
+        progress.bytes_total = 0;
+        progress.bytes_completed = 0;
+        progress.throughput = 0.0;
+        progress.eta_seconds = None;
+    }
+
+    async fn initialize_progress(&self, num_files: usize) {
+        let mut progress = self.progress.lock().await;
+        progress.status = DownloadStatus::Initiating;
+        progress.files_total = num_files;
+        progress.files_total_completed = 0;
+        progress.verification_total_completed = 0;
+        progress.current_file_downloaded = 0;
+        progress.current_file_total_size = 0;
+        progress.current_file_path = String::new();
+        progress.failed_files.clear();
+        progress.active_files.clear();
+        progress.served_by.clear();
+        progress.scan_files_total = 0;
+        progress.scan_files_completed = 0;
+        progress.scan_bytes_completed = 0;
+        progress.bytes_transferred = 0;
+        progress.bytes_total = 0;
+        progress.bytes_completed = 0;
+        progress.throughput = 0.0;
+        progress.eta_seconds = None;
+    }
+
+    async fn initialize_scan_progress(&self, num_files: usize) {
+        let mut progress = self.progress.lock().await;
+        set_active_status(&mut progress, DownloadStatus::Scanning);
+        progress.scan_files_total = num_files;
+        progress.scan_files_completed = 0;
+        progress.scan_bytes_completed = 0;
+    }
+
+    async fn update_scan_progress(&self, bytes_hashed: u64) {
+        let mut progress = self.progress.lock().await;
+        progress.scan_files_completed += 1;
+        progress.scan_bytes_completed += bytes_hashed;
+    }
+
+    async fn update_progress_for_up_to_date_files(&self, count: usize) {
+        let mut progress = self.progress.lock().await;
+        progress.files_total_completed += count;
+        progress.verification_total_completed += count;
+    }
+
+    async fn initialize_byte_progress(&self, plan: &SyncPlan) {
+        let bytes_to_download: u64 = plan.files_to_download().filter_map(|file| file.size).sum();
+
+        *self.throughput.lock().unwrap() = ThroughputMeter::new();
+
+        let mut progress = self.progress.lock().await;
+        progress.bytes_total = plan.up_to_date_bytes + bytes_to_download;
+        progress.bytes_completed = plan.up_to_date_bytes;
+        progress.throughput = 0.0;
+        progress.eta_seconds = None;
+    }
+
+    async fn update_progress_for_file(&self, file: &FileToDownload) {
+        self.emit(DownloadEvent::FileStarted {
+            path: file.path.clone(),
+        });
+
+        let mut progress = self.progress.lock().await;
+        set_active_status(&mut progress, DownloadStatus::Downloading);
+        progress.current_file_path = file.path.clone();
+        progress
+            .active_files
+            .insert(file.path.clone(), FileProgress::default());
+    }
+
+    async fn update_progress_for_completed_file(&self, file: &FileToDownload, source: &Source) {
+        self.emit(DownloadEvent::FileCompleted {
+            path: file.path.clone(),
+        });
+
+        let mut progress = self.progress.lock().await;
+        progress.files_total_completed += 1;
+        progress.verification_total_completed += 1;
+        progress.active_files.remove(&file.path);
+        progress
+            .served_by
+            .insert(file.path.clone(), source.base.clone());
+        progress.current_file_downloaded = 0;
+        progress.current_file_total_size = 0;
+    }
+
+    async fn update_progress_for_rejected_file(&self, error: &FileError) {
+        self.emit(DownloadEvent::FileFailed {
+            path: error.path.clone(),
+            error: error.message.clone(),
+        });
+
+        let mut progress = self.progress.lock().await;
+        progress
+            .failed_files
+            .insert(error.path.clone(), error.clone());
+    }
+
+    async fn update_progress_for_failed_file(&self, file: &FileToDownload, error: &DownloadError) {
+        self.emit(DownloadEvent::FileFailed {
+            path: file.path.clone(),
+            error: error.to_string(),
+        });
+
+        let mut progress = self.progress.lock().await;
+        // A failed file no longer counts towards the job's byte totals
+        if let Some(file_progress) = progress.active_files.get(&file.path) {
+            let downloaded = file_progress.downloaded;
+            progress.bytes_completed -= downloaded;
+        }
+        progress.bytes_total = progress.bytes_total.saturating_sub(file.size.unwrap_or(0));
+        progress.failed_files.insert(
+            file.path.clone(),
+            FileError {
+                path: file.path.clone(),
+                code: error.code(),
+                message: error.to_string(),
+            },
+        );
+        progress.active_files.remove(&file.path);
+    }
+
+    async fn clear_active_file(&self, file: &FileToDownload) {
+        let mut progress = self.progress.lock().await;
+        progress.active_files.remove(&file.path);
+    }
+
+    async fn finalize_progress(&self) {
+        let mut progress = self.progress.lock().await;
+        progress.status = if progress.failed_files.is_empty() {
+            DownloadStatus::Done
+        } else {
+            DownloadStatus::Error
+        };
+        progress.verification_total_completed = progress.files_total;
+    }
+
+    async fn prepare_for_download(&self, file: &FileToDownload) {
+        let mut progress = self.progress.lock().await;
+        set_active_status(&mut progress, DownloadStatus::Downloading);
+        progress.current_file_path = file.path.clone();
+        progress.current_file_downloaded = 0;
+        progress.current_file_total_size = 0;
+        let file_progress = progress.active_files.entry(file.path.clone()).or_default();
+        let previously_downloaded = file_progress.downloaded;
+        file_progress.downloaded = 0;
+        file_progress.total_size = 0;
+        // A retry starts this file over, resumed bytes are added back as they're counted
+        progress.bytes_completed -= previously_downloaded;
+    }
+
+    async fn update_attempt(&self, file: &FileToDownload, attempt: u32, max_attempts: u32) {
         let mut progress = self.progress.lock().await;
+        let file_progress = progress.active_files.entry(file.path.clone()).or_default();
+        file_progress.attempt = attempt;
+        file_progress.max_attempts = max_attempts;
+    }
+
+    async fn update_download_progress(
+        &self,
+        file: &FileToDownload,
+        downloaded: u64,
+        total_size: u64,
+        received: u64,
+    ) {
+        let throughput = self.throughput.lock().unwrap().record(received);
+
+        let mut progress = self.progress.lock().await;
+        progress.bytes_transferred += received;
+        progress.current_file_path = file.path.clone();
         progress.current_file_downloaded = downloaded;
         progress.current_file_total_size = total_size;
+        let file_progress = progress.active_files.entry(file.path.clone()).or_default();
+        let previously_downloaded = file_progress.downloaded;
+        file_progress.downloaded = downloaded;
+        file_progress.total_size = total_size;
+
+        progress.bytes_completed += downloaded - previously_downloaded;
+        progress.throughput = throughput;
+        progress.eta_seconds = (throughput > 0.0).then(|| {
+            progress
+                .bytes_total
+                .saturating_sub(progress.bytes_completed) as f64
+                / throughput
+        });
+    }
+
+    async fn load_hash_cache(&self, destination_folder: &Path) {
+        let config = self.config();
+
+        let cache = if config.use_hash_cache && !config.force_rehash {
+            let destination = destination_folder.to_path_buf();
+            // A corrupt or unreadable index only costs a full rehash
+            run_blocking(move || HashCache::load(&destination))
+                .await
+                .unwrap_or_else(|_| HashCache::empty(destination_folder))
+        } else {
+            HashCache::empty(destination_folder)
+        };
+
+        *self.hash_cache.lock().unwrap() = config.use_hash_cache.then_some(cache);
+    }
+
+    async fn save_hash_cache(&self, expected_files: &HashSet<PathBuf>) {
+        let cache = self.hash_cache.lock().unwrap().take();
+
+        if let Some(mut cache) = cache {
+            cache.retain(|path| expected_files.contains(path));
+            if let Err(e) = run_blocking(move || cache.save()).await {
+                self.emit(DownloadEvent::HashIndexFailed {
+                    error: e.to_string(),
+                });
+            }
+        }
+    }
+
+    fn cached_hash(&self, relative_path: &Path, fingerprint: &FileFingerprint) -> Option<String> {
+        let cache = self.hash_cache.lock().unwrap();
+        cache
+            .as_ref()?
+            .lookup(relative_path, fingerprint)
+            .map(str::to_string)
+    }
+
+    fn cache_hash(&self, relative_path: &Path, fingerprint: FileFingerprint, hash: &str) {
+        if let Some(cache) = self.hash_cache.lock().unwrap().as_mut() {
+            cache.insert(relative_path.to_path_buf(), fingerprint, hash.to_string());
+        }
     }
 
     async fn calculate_sha256(&self, file_path: &Path) -> Result<String, std::io::Error> {
-        let mut file = File::open(file_path)?;
-        let mut hasher = Sha256::new();
-        std::io::copy(&mut file, &mut hasher)?;
-        let result = hasher.finalize();
-        Ok(result.iter().map(|b| format!("{:02x}", b)).collect())
+        let file_path = file_path.to_path_buf();
+
+        run_blocking(move || sha256_file(&file_path)).await
+    }
+
+    async fn sleep_unless_cancelled(&self, duration: Duration) -> Result<(), DownloadError> {
+        let deadline = tokio::time::Instant::now() + duration;
+
+        while tokio::time::Instant::now() < deadline {
+            if self.is_cancelled() {
+                return Err(DownloadError::Cancelled);
+            }
+            let remaining = deadline - tokio::time::Instant::now();
+            tokio::time::sleep(remaining.min(Duration::from_millis(100))).await;
+        }
+
+        Ok(())
+    }
+
+    /// Waits until the job is resumed, or fails if it is cancelled meanwhile.
+    async fn wait_while_paused(&self) -> Result<(), DownloadError> {
+        let mut paused = self.paused.subscribe();
+
+        while *paused.borrow_and_update() {
+            if self.is_cancelled() {
+                return Err(DownloadError::Cancelled);
+            }
+            // The sender lives as long as self, so this can't fail
+            let _ = paused.changed().await;
+        }
+
+        if self.is_cancelled() {
+            return Err(DownloadError::Cancelled);
+        }
+        Ok(())
+    }
+
+    fn is_paused(&self) -> bool {
+        *self.paused.borrow()
     }
 
     fn is_cancelled(&self) -> bool {
@@ -318,12 +1645,473 @@ impl DownloadManager {
             .load(std::sync::atomic::Ordering::SeqCst)
     }
 
+    pub fn config(&self) -> DownloadConfig {
+        self.config.read().unwrap().clone()
+    }
+
+    pub fn set_rate_limit(&self, limit: RateLimit) {
+        self.rate_limiter.set_limit(limit);
+    }
+
     pub fn cancel(&self) {
         self.cancellation_flag
             .store(true, std::sync::atomic::Ordering::SeqCst);
+        // Wake anything waiting on a pause so it notices the cancellation
+        self.paused.send_replace(false);
+    }
+
+    /// Suspends the running job. Transfers stop at the next chunk and keep
+    /// their partial files; nothing new is started until `resume`.
+    pub async fn pause(&self) -> bool {
+        let mut progress = self.progress.lock().await;
+        if !matches!(
+            progress.status,
+            DownloadStatus::Initiating | DownloadStatus::Scanning | DownloadStatus::Downloading
+        ) {
+            return false;
+        }
+
+        self.paused.send_replace(true);
+        *self.status_before_pause.lock().unwrap() = Some(progress.status);
+        progress.status = DownloadStatus::Paused;
+        progress.throughput = 0.0;
+        progress.eta_seconds = None;
+        true
+    }
+
+    /// Forgets any pause, so a run that ended while paused can't hold up the next.
+    fn clear_pause(&self) {
+        self.paused.send_replace(false);
+        *self.status_before_pause.lock().unwrap() = None;
+    }
+
+    pub async fn resume(&self) -> bool {
+        let mut progress = self.progress.lock().await;
+        if progress.status != DownloadStatus::Paused {
+            return false;
+        }
+
+        // Time spent paused shouldn't drag the rate down
+        *self.throughput.lock().unwrap() = ThroughputMeter::new();
+        progress.status = self
+            .status_before_pause
+            .lock()
+            .unwrap()
+            .take()
+            .unwrap_or(DownloadStatus::Downloading);
+        self.paused.send_replace(false);
+        true
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<DownloadEvent> {
+        self.events.subscribe()
+    }
+
+    fn emit(&self, event: DownloadEvent) {
+        // Sending only fails when nobody is listening
+        let _ = self.events.send(event);
     }
 
     pub async fn get_progress(&self) -> DownloadProgress {
         self.progress.lock().await.clone()
     }
-}
\ No newline at end of file
+}
+
+/// Moves a running job to `status`, unless it was paused in the meantime.
+fn set_active_status(progress: &mut DownloadProgress, status: DownloadStatus) {
+    if progress.status != DownloadStatus::Paused {
+        progress.status = status;
+    }
+}
+
+/// Which paths under the destination Scarlet owns, and which of those the
+/// manifest still expects.
+struct CleanupScope {
+    managed_dirs: HashSet<PathBuf>,
+    keep_paths: HashSet<PathBuf>,
+}
+
+impl CleanupScope {
+    fn new(destination_folder: &Path, expected_files: &HashSet<PathBuf>) -> Self {
+        let base_path = PathBuf::from(destination_folder);
+        // A path climbing out of the destination would make its parent managed
+        let expected_files: Vec<&PathBuf> = expected_files
+            .iter()
+            .filter(|path| {
+                path.components()
+                    .all(|component| matches!(component, Component::Normal(_)))
+            })
+            .collect();
+
+        // Extract managed directories
+        let managed_dirs: HashSet<PathBuf> = expected_files
+            .iter()
+            .filter_map(|path| path.components().next())
+            .map(|comp| base_path.join(comp.as_os_str()))
+            .collect();
+
+        // Collect all paths that should be kept
+        let mut keep_paths = HashSet::new();
+        for expected_file in &expected_files {
+            let full_path = base_path.join(expected_file);
+            keep_paths.insert(full_path.clone());
+            // Keep interrupted downloads around so they can be resumed
+            keep_paths.insert(partial_path(&full_path));
+            keep_paths.insert(validator_path(&full_path));
+            keep_paths.insert(segments_path(&full_path));
+            // Add all parent directories to keep_paths
+            for ancestor in full_path.ancestors().skip(1) {
+                if ancestor.starts_with(&base_path) {
+                    keep_paths.insert(ancestor.to_path_buf());
+                } else {
+                    break;
+                }
+            }
+        }
+
+        Self {
+            managed_dirs,
+            keep_paths,
+        }
+    }
+
+    fn managed_dir(&self, path: &Path) -> Option<&Path> {
+        self.managed_dirs
+            .iter()
+            .find(|dir| path.starts_with(dir))
+            .map(PathBuf::as_path)
+    }
+
+    fn is_unexpected(&self, path: &Path) -> bool {
+        // Only process files and directories within managed directories
+        self.managed_dirs.iter().any(|dir| path.starts_with(dir)) && !self.keep_paths.contains(path)
+    }
+}
+
+/// Something cleanup would take out of the destination.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CleanupEntry {
+    /// Relative to the destination.
+    pub path: PathBuf,
+    pub is_dir: bool,
+    /// For a directory, the total size of the files removed beneath it.
+    pub size: u64,
+    /// The managed folder the path belongs to, relative to the destination.
+    pub managed_dir: PathBuf,
+}
+
+/// Unexpected files, and directories that would be empty once those are gone,
+/// in the order they would be removed.
+fn preview_unexpected(
+    destination_folder: &Path,
+    expected_files: &HashSet<PathBuf>,
+) -> std::io::Result<Vec<CleanupEntry>> {
+    // Nothing has been synced into a destination that doesn't exist yet
+    if !destination_folder.exists() {
+        return Ok(Vec::new());
+    }
+
+    let scope = CleanupScope::new(destination_folder, expected_files);
+    let relative = |path: &Path| {
+        path.strip_prefix(destination_folder)
+            .map(Path::to_path_buf)
+            .unwrap_or_else(|_| path.to_path_buf())
+    };
+
+    let mut entries = Vec::new();
+    let mut removing = HashSet::new();
+    // Bytes removed under each directory, filled in before the directory itself is visited
+    let mut removed_below: HashMap<PathBuf, u64> = HashMap::new();
+
+    // Walk the directory tree bottom-up
+    for entry in WalkDir::new(destination_folder).contents_first(true) {
+        let entry = entry?;
+        let path = entry.path();
+        let managed_dir = match scope.managed_dir(path) {
+            Some(dir) if scope.is_unexpected(path) => relative(dir),
+            _ => continue,
+        };
+
+        let is_dir = entry.file_type().is_dir();
+        let size = if is_dir {
+            // Only directories that end up empty are removed
+            let mut children = fs::read_dir(path)?;
+            if !children.all(|child| child.is_ok_and(|child| removing.contains(&child.path()))) {
+                continue;
+            }
+            removed_below.remove(path).unwrap_or(0)
+        } else {
+            entry.metadata()?.len()
+        };
+
+        if let Some(parent) = path.parent() {
+            *removed_below.entry(parent.to_path_buf()).or_default() += size;
+        }
+        removing.insert(path.to_path_buf());
+        entries.push(CleanupEntry {
+            path: relative(path),
+            is_dir,
+            size,
+            managed_dir,
+        });
+    }
+
+    Ok(entries)
+}
+
+/// Removes what a fresh preview finds, limited to `approved` paths when given,
+/// and returns the paths actually removed, relative to the destination.
+/// Directories are only removed if they are empty by then.
+fn remove_unexpected_files(
+    destination_folder: &Path,
+    expected_files: &HashSet<PathBuf>,
+    approved: Option<&HashSet<PathBuf>>,
+    policy: CleanupPolicy,
+) -> std::io::Result<Vec<PathBuf>> {
+    let mut remover = Remover::new(destination_folder, policy);
+    let mut removed = Vec::new();
+
+    for entry in preview_unexpected(destination_folder, expected_files)? {
+        if approved.is_some_and(|approved| !approved.contains(&entry.path)) {
+            continue;
+        }
+
+        let path = destination_folder.join(&entry.path);
+        if entry.is_dir {
+            if fs::read_dir(&path)?.next().is_some() {
+                continue;
+            }
+            remover.remove_empty_dir(&path)?;
+        } else {
+            remover.remove_file(&path)?;
+        }
+        removed.push(entry.path);
+    }
+
+    Ok(removed)
+}
+
+/// A manifest path as a path relative to the destination. Manifest paths are
+/// rooted at the repository with forward slashes, so anything that could
+/// point elsewhere or mean something else on Windows is refused.
+pub fn relative_path(path: &str) -> Result<PathBuf, DownloadError> {
+    let unsafe_path = |reason| DownloadError::UnsafePath {
+        path: path.to_string(),
+        reason,
+    };
+
+    let trimmed = path.trim_start_matches('/');
+    if trimmed.is_empty() {
+        return Err(unsafe_path("it is empty"));
+    }
+    if trimmed.contains('\\') {
+        return Err(unsafe_path("it contains a backslash"));
+    }
+    if trimmed.contains(':') {
+        return Err(unsafe_path("it contains a drive or stream separator"));
+    }
+    if trimmed.contains('\0') {
+        return Err(unsafe_path("it contains a null byte"));
+    }
+
+    let mut relative = PathBuf::new();
+    for component in trimmed.split('/') {
+        if component.is_empty() || component == "." {
+            return Err(unsafe_path(
+                "it has an empty or current-directory component",
+            ));
+        }
+        if component == ".." {
+            return Err(unsafe_path("it has a parent-directory component"));
+        }
+        if component.ends_with('.') || component.ends_with(' ') {
+            return Err(unsafe_path("it has a name ending in a dot or space"));
+        }
+        if relative.as_os_str().is_empty() && component == QUARANTINE_DIR_NAME {
+            return Err(unsafe_path("it is inside Scarlet's quarantine folder"));
+        }
+        if is_reserved_name(component) {
+            return Err(unsafe_path("it uses a reserved device name"));
+        }
+        relative.push(component);
+    }
+
+    Ok(relative)
+}
+
+/// Names Windows maps to devices regardless of extension, like `nul.txt`.
+fn is_reserved_name(component: &str) -> bool {
+    let stem = component.split('.').next().unwrap_or(component);
+    let stem = stem.to_ascii_uppercase();
+    matches!(stem.as_str(), "CON" | "PRN" | "AUX" | "NUL")
+        || ((stem.starts_with("COM") || stem.starts_with("LPT"))
+            && stem.len() == 4
+            && stem.as_bytes()[3].is_ascii_digit()
+            && stem.as_bytes()[3] != b'0')
+}
+
+/// The destination-relative paths of the entries that pass validation.
+pub fn expected_files(files: &[FileToDownload]) -> HashSet<PathBuf> {
+    files
+        .iter()
+        .filter_map(|file| relative_path(&file.path).ok())
+        .collect()
+}
+
+/// Whether writing to `path` would stay under `root`, which must already be
+/// canonical. The nearest existing ancestor is resolved, so a symlinked
+/// folder or file pointing elsewhere is caught before anything is created.
+fn stays_within(root: &Path, path: &Path) -> bool {
+    let mut existing = path;
+    loop {
+        match fs::symlink_metadata(existing) {
+            Ok(_) => break,
+            Err(_) => match existing.parent() {
+                Some(parent) => existing = parent,
+                None => return false,
+            },
+        }
+    }
+
+    // A dangling symlink can't be resolved, and writing through it would
+    // create its target wherever that is
+    existing
+        .canonicalize()
+        .is_ok_and(|resolved| resolved.starts_with(root))
+}
+
+/// Whether `path` is one of Scarlet's own files, such as a partial download or
+/// the hash index, rather than content that belongs to a repository.
+pub fn is_sync_artifact(path: &Path) -> bool {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    file_name == INDEX_FILE_NAME
+        || (file_name.starts_with(INDEX_FILE_NAME) && file_name.ends_with(".tmp"))
+        || [".part", ".part.validator", ".part.segments"]
+            .iter()
+            .any(|suffix| file_name.ends_with(suffix))
+}
+
+pub fn sha256_file(path: &Path) -> std::io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(to_hex(&hasher.finalize()))
+}
+
+/// Runs filesystem or hashing work on the blocking pool so the reactor stays
+/// free to answer progress requests.
+pub(crate) async fn run_blocking<T, F>(f: F) -> std::io::Result<T>
+where
+    F: FnOnce() -> std::io::Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(std::io::Error::other)?
+}
+
+fn to_hex(digest: &[u8]) -> String {
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn partial_path(file_path: &Path) -> PathBuf {
+    sibling_with_suffix(file_path, ".part")
+}
+
+fn validator_path(file_path: &Path) -> PathBuf {
+    sibling_with_suffix(file_path, ".part.validator")
+}
+
+fn segments_path(file_path: &Path) -> PathBuf {
+    sibling_with_suffix(file_path, ".part.segments")
+}
+
+fn sibling_with_suffix(file_path: &Path, suffix: &str) -> PathBuf {
+    let mut file_name = file_path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(suffix);
+    file_path.with_file_name(file_name)
+}
+
+/// Swaps a verified download in for the target, so readers only ever see
+/// the old file or the complete new one.
+fn replace_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    fs::rename(from, to)?;
+
+    // Persist the rename itself; Windows has no directory handles to sync
+    #[cfg(unix)]
+    if let Some(parent) = to.parent() {
+        File::open(parent)?.sync_all()?;
+    }
+
+    Ok(())
+}
+
+async fn remove_if_exists(path: &Path) -> std::io::Result<()> {
+    match tokio::fs::remove_file(path).await {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+/// Strong validator to send back as `If-Range`, preferring the ETag.
+fn response_validator(headers: &HeaderMap) -> Option<String> {
+    let etag = headers
+        .get(ETAG)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| !v.starts_with("W/"));
+
+    etag.or_else(|| headers.get(LAST_MODIFIED).and_then(|v| v.to_str().ok()))
+        .map(str::to_string)
+}
+
+/// First byte position of a `Content-Range: bytes <start>-<end>/<total>` header.
+fn content_range_start(headers: &HeaderMap) -> Option<u64> {
+    let value = headers.get(CONTENT_RANGE)?.to_str().ok()?;
+    let range = value.strip_prefix("bytes ")?;
+    range.split('-').next()?.trim().parse().ok()
+}
+
+fn check_status(response: &Response) -> Result<(), DownloadError> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(());
+    }
+
+    let code = status.as_u16();
+    let url = response.url().to_string();
+    let retry_after = retry_after(response.headers());
+
+    Err(match status {
+        StatusCode::NOT_FOUND | StatusCode::GONE => DownloadError::NotFound { status: code, url },
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+            DownloadError::Unauthorized { status: code, url }
+        }
+        StatusCode::TOO_MANY_REQUESTS => DownloadError::RateLimited {
+            status: code,
+            url,
+            retry_after,
+        },
+        StatusCode::SERVICE_UNAVAILABLE if retry_after.is_some() => DownloadError::RateLimited {
+            status: code,
+            url,
+            retry_after,
+        },
+        _ if status.is_server_error() => DownloadError::ServerError { status: code, url },
+        _ => DownloadError::UnexpectedStatus { status: code, url },
+    })
+}
+
+/// Parses `Retry-After` given either as delay seconds or as an HTTP date.
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(
+        date.duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO),
+    )
+}
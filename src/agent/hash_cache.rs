@@ -0,0 +1,177 @@
+use std::collections::HashMap;
+use std::fs::{self, Metadata};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::UNIX_EPOCH;
+
+pub const INDEX_FILE_NAME: &str = ".scarlet-index";
+
+const INDEX_HEADER: &str = "# scarlet hash index v1";
+
+/// Numbers each save's temporary file within this process.
+static NEXT_SAVE: AtomicU32 = AtomicU32::new(0);
+
+/// Metadata that must be unchanged for a cached hash to be trusted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileFingerprint {
+    pub size: u64,
+    pub mtime_nanos: u128,
+    pub inode: u64,
+}
+
+impl FileFingerprint {
+    pub fn from_metadata(metadata: &Metadata) -> Self {
+        let mtime_nanos = metadata
+            .modified()
+            .ok()
+            .and_then(|mtime| mtime.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |mtime| mtime.as_nanos());
+
+        Self {
+            size: metadata.len(),
+            mtime_nanos,
+            inode: inode(metadata),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn inode(metadata: &Metadata) -> u64 {
+    std::os::unix::fs::MetadataExt::ino(metadata)
+}
+
+#[cfg(not(unix))]
+fn inode(_metadata: &Metadata) -> u64 {
+    0
+}
+
+#[derive(Debug, Clone)]
+struct CacheEntry {
+    fingerprint: FileFingerprint,
+    sha256: String,
+}
+
+/// Index of known file hashes stored in the destination folder, keyed by
+/// path relative to it.
+#[derive(Debug)]
+pub struct HashCache {
+    path: PathBuf,
+    entries: HashMap<PathBuf, CacheEntry>,
+}
+
+impl HashCache {
+    pub fn empty(destination_folder: &Path) -> Self {
+        Self {
+            path: destination_folder.join(INDEX_FILE_NAME),
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Loads the index, treating a missing file as empty and skipping lines
+    /// that cannot be parsed.
+    pub fn load(destination_folder: &Path) -> std::io::Result<Self> {
+        let mut cache = Self::empty(destination_folder);
+
+        let contents = match fs::read_to_string(&cache.path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(cache),
+            Err(e) => return Err(e),
+        };
+
+        for line in contents.lines() {
+            if line.starts_with('#') {
+                continue;
+            }
+            if let Some((path, entry)) = parse_line(line) {
+                cache.entries.insert(path, entry);
+            }
+        }
+
+        Ok(cache)
+    }
+
+    /// Cached hash for `path`, or `None` if the file changed since it was recorded.
+    pub fn lookup(&self, path: &Path, fingerprint: &FileFingerprint) -> Option<&str> {
+        self.entries
+            .get(path)
+            .filter(|entry| entry.fingerprint == *fingerprint)
+            .map(|entry| entry.sha256.as_str())
+    }
+
+    pub fn insert(&mut self, path: PathBuf, fingerprint: FileFingerprint, sha256: String) {
+        self.entries.insert(
+            path,
+            CacheEntry {
+                fingerprint,
+                sha256,
+            },
+        );
+    }
+
+    pub fn retain(&mut self, mut keep: impl FnMut(&Path) -> bool) {
+        self.entries.retain(|path, _| keep(path));
+    }
+
+    pub fn save(&self) -> std::io::Result<()> {
+        let mut contents = String::from(INDEX_HEADER);
+        contents.push('\n');
+
+        let mut paths: Vec<&PathBuf> = self.entries.keys().collect();
+        paths.sort();
+
+        for path in paths {
+            let entry = &self.entries[path];
+            contents.push_str(&format!(
+                "{}\t{}\t{}\t{}\t{}\n",
+                entry.sha256,
+                entry.fingerprint.size,
+                entry.fingerprint.mtime_nanos,
+                entry.fingerprint.inode,
+                path.to_string_lossy()
+            ));
+        }
+
+        // Saves running at the same time, such as a plan during a sync or two
+        // processes, each write their own file and the last rename wins
+        let mut temp_name = self.path.as_os_str().to_os_string();
+        temp_name.push(format!(
+            ".{}-{}.tmp",
+            std::process::id(),
+            NEXT_SAVE.fetch_add(1, Ordering::Relaxed)
+        ));
+        let temp_path = PathBuf::from(temp_name);
+
+        let result = fs::File::create(&temp_path).and_then(|mut file| {
+            file.write_all(contents.as_bytes())?;
+            file.sync_all()?;
+            fs::rename(&temp_path, &self.path)
+        });
+        if result.is_err() {
+            let _ = fs::remove_file(&temp_path);
+        }
+        result
+    }
+}
+
+fn parse_line(line: &str) -> Option<(PathBuf, CacheEntry)> {
+    // The path comes last so it may itself contain tabs
+    let mut fields = line.splitn(5, '\t');
+    let sha256 = fields.next()?.to_string();
+    let size = fields.next()?.parse().ok()?;
+    let mtime_nanos = fields.next()?.parse().ok()?;
+    let inode = fields.next()?.parse().ok()?;
+    let path = PathBuf::from(fields.next()?);
+
+    Some((
+        path,
+        CacheEntry {
+            fingerprint: FileFingerprint {
+                size,
+                mtime_nanos,
+                inode,
+            },
+            sha256,
+        },
+    ))
+}
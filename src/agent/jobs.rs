@@ -0,0 +1,257 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+
+use thiserror::Error;
+use tokio::sync::{broadcast, watch};
+
+use crate::download::{
+    DownloadConfig, DownloadEvent, DownloadManager, FileToDownload, SyncSummary,
+};
+use crate::rate_limit::{RateLimit, RateLimiter};
+
+pub type JobId = u32;
+
+/// How a job ended. The error is kept as its message since `DownloadError`
+/// can't be cloned out to every waiter.
+pub type JobOutcome = Result<SyncSummary, String>;
+
+#[derive(Debug, Error)]
+pub enum JobError {
+    #[error("A sync or cleanup is already running for {0}")]
+    DestinationBusy(String),
+    #[error("No job with id {0}")]
+    NotFound(JobId),
+}
+
+/// One sync into one destination folder, with its own manager so progress,
+/// cancellation and pausing never leak between jobs.
+pub struct Job {
+    pub id: JobId,
+    pub destination: PathBuf,
+    /// `destination` resolved, for telling whether two jobs share a folder.
+    folder: PathBuf,
+    pub manager: Arc<DownloadManager>,
+    outcome: watch::Sender<Option<JobOutcome>>,
+}
+
+impl Job {
+    pub fn is_finished(&self) -> bool {
+        self.outcome.borrow().is_some()
+    }
+
+    fn finish(&self, outcome: JobOutcome) {
+        self.outcome.send_replace(Some(outcome));
+    }
+
+    /// Resolves once the job has finished, immediately if it already has.
+    pub async fn wait(&self) -> JobOutcome {
+        let mut outcome = self.outcome.subscribe();
+        loop {
+            if let Some(outcome) = outcome.borrow_and_update().clone() {
+                return outcome;
+            }
+            // The sender lives as long as the job, so this can't fail
+            let _ = outcome.changed().await;
+        }
+    }
+}
+
+pub struct JobRegistry {
+    next_id: AtomicU32,
+    jobs: Mutex<HashMap<JobId, Arc<Job>>>,
+    /// Resolved folders held by a `FolderLease`.
+    leases: Mutex<HashSet<PathBuf>>,
+    events: broadcast::Sender<(JobId, DownloadEvent)>,
+    rate_limiter: Arc<RateLimiter>,
+}
+
+impl JobRegistry {
+    pub fn new() -> Self {
+        Self {
+            next_id: AtomicU32::new(1),
+            jobs: Mutex::new(HashMap::new()),
+            leases: Mutex::new(HashSet::new()),
+            events: broadcast::channel(1024).0,
+            rate_limiter: Arc::new(RateLimiter::new(RateLimit::default())),
+        }
+    }
+
+    /// Registers a job for `destination`. Two jobs writing into the same folder
+    /// would trample each other's partial files, so that is refused while the
+    /// earlier one is still running. A finished job for the folder is replaced.
+    pub fn create(
+        &self,
+        destination: impl AsRef<Path>,
+        config: DownloadConfig,
+    ) -> Result<Arc<Job>, JobError> {
+        let destination = destination.as_ref().to_path_buf();
+        let folder = resolve_folder(&destination);
+        let mut jobs = self.jobs.lock().unwrap();
+
+        let running = jobs
+            .values()
+            .any(|job| job.folder == folder && !job.is_finished());
+        if running || self.leases.lock().unwrap().contains(&folder) {
+            return Err(JobError::DestinationBusy(
+                destination.to_string_lossy().into_owned(),
+            ));
+        }
+        jobs.retain(|_, job| job.folder != folder);
+
+        let job = Arc::new(Job {
+            id: self.next_id.fetch_add(1, Ordering::SeqCst),
+            destination,
+            folder,
+            manager: Arc::new(DownloadManager::with_rate_limiter(
+                config,
+                self.rate_limiter.clone(),
+            )),
+            outcome: watch::channel(None).0,
+        });
+        jobs.insert(job.id, job.clone());
+
+        Ok(job)
+    }
+
+    /// Keeps jobs out of `destination` until the lease is dropped, for work
+    /// such as cleanup that mustn't run underneath a sync. Fails if a job or
+    /// another lease already has the folder.
+    pub fn lease(&self, destination: impl AsRef<Path>) -> Result<FolderLease<'_>, JobError> {
+        let destination = destination.as_ref();
+        let folder = resolve_folder(destination);
+        let jobs = self.jobs.lock().unwrap();
+        let mut leases = self.leases.lock().unwrap();
+
+        let running = jobs
+            .values()
+            .any(|job| job.folder == folder && !job.is_finished());
+        if running || !leases.insert(folder.clone()) {
+            return Err(JobError::DestinationBusy(
+                destination.to_string_lossy().into_owned(),
+            ));
+        }
+
+        Ok(FolderLease {
+            registry: self,
+            folder,
+        })
+    }
+
+    pub fn get(&self, id: JobId) -> Result<Arc<Job>, JobError> {
+        self.jobs
+            .lock()
+            .unwrap()
+            .get(&id)
+            .cloned()
+            .ok_or(JobError::NotFound(id))
+    }
+
+    /// All known jobs, oldest first.
+    pub fn list(&self) -> Vec<Arc<Job>> {
+        let mut jobs: Vec<Arc<Job>> = self.jobs.lock().unwrap().values().cloned().collect();
+        jobs.sort_by_key(|job| job.id);
+        jobs
+    }
+
+    /// Runs `job` to completion, tagging its events for registry subscribers.
+    pub async fn run(&self, job: &Job, files: Vec<FileToDownload>) -> JobOutcome {
+        let mut events = job.manager.subscribe();
+        let download = job.manager.download(&job.destination, files);
+        tokio::pin!(download);
+
+        let result = loop {
+            tokio::select! {
+                result = &mut download => break result,
+                event = events.recv() => {
+                    if let Ok(event) = event {
+                        self.emit(job.id, event);
+                    }
+                }
+            }
+        };
+        // Whatever was sent after the last poll, including SyncFinished
+        while let Ok(event) = events.try_recv() {
+            self.emit(job.id, event);
+        }
+
+        let outcome = result.map_err(|e| e.to_string());
+        job.finish(outcome.clone());
+        outcome
+    }
+
+    pub fn latest(&self) -> Option<Arc<Job>> {
+        self.list().pop()
+    }
+
+    pub fn remove(&self, id: JobId) -> Result<(), JobError> {
+        let mut jobs = self.jobs.lock().unwrap();
+        match jobs.get(&id) {
+            Some(job) if !job.is_finished() => Err(JobError::DestinationBusy(
+                job.destination.to_string_lossy().into_owned(),
+            )),
+            Some(_) => {
+                jobs.remove(&id);
+                Ok(())
+            }
+            None => Err(JobError::NotFound(id)),
+        }
+    }
+
+    /// Sets the limit shared by all jobs, running or not.
+    pub fn set_rate_limit(&self, limit: RateLimit) {
+        self.rate_limiter.set_limit(limit);
+    }
+
+    /// Events from every job, tagged with the job they came from.
+    pub fn subscribe(&self) -> broadcast::Receiver<(JobId, DownloadEvent)> {
+        self.events.subscribe()
+    }
+
+    fn emit(&self, id: JobId, event: DownloadEvent) {
+        // Sending only fails when nobody is listening
+        let _ = self.events.send((id, event));
+    }
+}
+
+/// A folder reserved with `JobRegistry::lease`, released on drop.
+pub struct FolderLease<'a> {
+    registry: &'a JobRegistry,
+    folder: PathBuf,
+}
+
+impl Drop for FolderLease<'_> {
+    fn drop(&mut self) {
+        self.registry.leases.lock().unwrap().remove(&self.folder);
+    }
+}
+
+/// `path` made absolute with `.` and `..` removed and symlinks resolved, so
+/// every spelling of a folder maps to the same path. The folder itself may
+/// not exist yet; only the part of the path that does is resolved.
+fn resolve_folder(path: &Path) -> PathBuf {
+    let absolute = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+    let mut lexical = PathBuf::new();
+    for component in absolute.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                lexical.pop();
+            }
+            component => lexical.push(component),
+        }
+    }
+
+    for existing in lexical.ancestors() {
+        if let Ok(resolved) = existing.canonicalize() {
+            let rest = lexical.strip_prefix(existing).unwrap_or(Path::new(""));
+            return if rest.as_os_str().is_empty() {
+                resolved
+            } else {
+                resolved.join(rest)
+            };
+        }
+    }
+    lexical
+}
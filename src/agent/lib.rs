@@ -1,54 +1,316 @@
-use std::sync::Arc;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use lazy_static::lazy_static;
 use neon::prelude::*;
 use tokio::runtime::Runtime;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::task::JoinHandle;
+use tokio::time::MissedTickBehavior;
 
-use crate::download::{DownloadManager, FileToDownload};
+use crate::cleanup::QuarantineSnapshot;
+use crate::download::{
+    expected_files, run_blocking, CleanupEntry, DownloadConfig, DownloadEvent, DownloadManager,
+    DownloadProgress, DownloadStatus, FileError, FileToDownload, SyncSummary,
+};
+use crate::jobs::{Job, JobId, JobRegistry};
+use crate::manifest::Manifest;
+use crate::mirrors::{rank, MirrorProbe};
+use crate::rate_limit::{RateLimit, ScheduleWindow};
 
-mod download;
+pub mod cleanup;
+pub mod download;
+mod hash_cache;
+mod jobs;
+pub mod manifest;
+mod mirrors;
+mod rate_limit;
+mod segments;
 mod test;
 // mod test;
 
 lazy_static! {
-    static ref DOWNLOAD_MANAGER: Arc<DownloadManager> = Arc::new(DownloadManager::new());
+    static ref JOBS: JobRegistry = JobRegistry::new();
     static ref RUNTIME: Runtime = Runtime::new().unwrap();
+    static ref MANIFEST_CLIENT: reqwest::Client = manifest::client();
+    static ref SUBSCRIPTIONS: Mutex<HashMap<u32, JoinHandle<()>>> = Mutex::new(HashMap::new());
 }
 
-fn start_download(mut cx: FunctionContext) -> JsResult<JsPromise> {
-    let destination = cx.argument::<JsString>(0)?.value(&mut cx);
-    let files_array = cx.argument::<JsArray>(1)?;
+static NEXT_SUBSCRIPTION_ID: AtomicU32 = AtomicU32::new(1);
+
+fn parse_files(cx: &mut FunctionContext, index: usize) -> NeonResult<Vec<FileToDownload>> {
+    let files_array = cx.argument::<JsArray>(index)?;
+
+    let mut files = Vec::new();
+    for value in files_array.to_vec(cx)? {
+        let obj = value.downcast_or_throw::<JsObject, _>(cx)?;
+        files.push(FileToDownload {
+            url: obj.get::<JsString, _, _>(cx, "url")?.value(cx),
+            path: obj.get::<JsString, _, _>(cx, "path")?.value(cx),
+            sha256_hash: obj.get::<JsString, _, _>(cx, "sha256_hash")?.value(cx),
+            size: obj
+                .get_opt::<JsNumber, _, _>(cx, "size")?
+                .map(|size| size.value(cx) as u64),
+            mirrors: parse_strings(cx, obj, "mirrors")?,
+        });
+    }
 
-    let files: Vec<FileToDownload> = files_array
-        .to_vec(&mut cx)?
+    Ok(files)
+}
+
+/// An optional array of strings on `obj`, empty when it's missing.
+fn parse_strings(
+    cx: &mut FunctionContext,
+    obj: Handle<JsObject>,
+    key: &str,
+) -> NeonResult<Vec<String>> {
+    let array = match obj.get_opt::<JsArray, _, _>(cx, key)? {
+        Some(array) => array,
+        None => return Ok(Vec::new()),
+    };
+
+    array
+        .to_vec(cx)?
         .into_iter()
-        .map(|v| {
-            let obj = v.downcast::<JsObject, _>(&mut cx).unwrap();
-            FileToDownload {
-                url: obj
-                    .get::<JsString, _, _>(&mut cx, "url")
-                    .unwrap()
-                    .value(&mut cx),
-                path: obj
-                    .get::<JsString, _, _>(&mut cx, "path")
-                    .unwrap()
-                    .value(&mut cx),
-                sha256_hash: obj
-                    .get::<JsString, _, _>(&mut cx, "sha256_hash")
-                    .unwrap()
-                    .value(&mut cx),
-            }
+        .map(|value| Ok(value.downcast_or_throw::<JsString, _>(cx)?.value(cx)))
+        .collect()
+}
+
+/// The optional array of paths at `index`, or `None` when it isn't an array.
+fn parse_paths(cx: &mut FunctionContext, index: usize) -> NeonResult<Option<Vec<PathBuf>>> {
+    let array = match cx.argument_opt(index) {
+        Some(value) if value.is_a::<JsArray, _>(cx) => value.downcast_or_throw::<JsArray, _>(cx)?,
+        _ => return Ok(None),
+    };
+
+    array
+        .to_vec(cx)?
+        .into_iter()
+        .map(|value| {
+            Ok(PathBuf::from(
+                value.downcast_or_throw::<JsString, _>(cx)?.value(cx),
+            ))
         })
-        .collect();
+        .collect::<NeonResult<_>>()
+        .map(Some)
+}
+
+/// The optional job id at `index`, or `None` when it was left out.
+fn parse_job_id(cx: &mut FunctionContext, index: usize) -> NeonResult<Option<JobId>> {
+    match cx.argument_opt(index) {
+        Some(value) if !value.is_a::<JsUndefined, _>(cx) && !value.is_a::<JsNull, _>(cx) => {
+            let id = value.downcast_or_throw::<JsNumber, _>(cx)?.value(cx);
+            Ok(Some(id as JobId))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// The job named at `index`, or every job when no id was given.
+fn parse_jobs(cx: &mut FunctionContext, index: usize) -> NeonResult<Vec<Arc<Job>>> {
+    match parse_job_id(cx, index)? {
+        Some(id) => match JOBS.get(id) {
+            Ok(job) => Ok(vec![job]),
+            Err(e) => cx.throw_error(e.to_string()),
+        },
+        None => Ok(JOBS.list()),
+    }
+}
+
+fn start_download(mut cx: FunctionContext) -> JsResult<JsNumber> {
+    let destination = cx.argument::<JsString>(0)?.value(&mut cx);
+    let files = parse_files(&mut cx, 1)?;
+
+    let config = parse_download_options(&mut cx, 2)?;
+
+    let job = match JOBS.create(destination, config) {
+        Ok(job) => job,
+        Err(e) => return cx.throw_error(e.to_string()),
+    };
+    let id = job.id;
+
+    RUNTIME.spawn(async move {
+        // The outcome stays on the job for wait_for_job
+        let _ = JOBS.run(&job, files).await;
+    });
+
+    Ok(cx.number(id))
+}
+
+fn wait_for_job(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let id = cx.argument::<JsNumber>(0)?.value(&mut cx) as JobId;
+    let job = match JOBS.get(id) {
+        Ok(job) => job,
+        Err(e) => return cx.throw_error(e.to_string()),
+    };
+
+    let (deferred, promise) = cx.promise();
+    let channel = cx.channel();
+
+    RUNTIME.spawn(async move {
+        let outcome = job.wait().await;
+        deferred.settle_with(&channel, move |mut cx| match outcome {
+            Ok(summary) => summary_to_js(&mut cx, &summary),
+            Err(e) => cx.throw_error(e),
+        });
+    });
+
+    Ok(promise)
+}
+
+fn list_jobs(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let (deferred, promise) = cx.promise();
+    let channel = cx.channel();
+
+    RUNTIME.spawn(async move {
+        let mut jobs = Vec::new();
+        for job in JOBS.list() {
+            let progress = job.manager.get_progress().await;
+            let probes = job.manager.mirror_probes();
+            jobs.push((job, progress, probes));
+        }
+
+        deferred.settle_with(&channel, move |mut cx| {
+            let array = cx.empty_array();
+            for (i, (job, progress, probes)) in jobs.iter().enumerate() {
+                let obj = cx.empty_object();
+                let id = cx.number(job.id);
+                obj.set(&mut cx, "jobId", id)?;
+                let destination = cx.string(job.destination.to_string_lossy());
+                obj.set(&mut cx, "destination", destination)?;
+                let finished = cx.boolean(job.is_finished());
+                obj.set(&mut cx, "finished", finished)?;
+                let progress = progress_to_js(&mut cx, progress)?;
+                obj.set(&mut cx, "progress", progress)?;
+                let probes = probes_to_js(&mut cx, probes)?;
+                obj.set(&mut cx, "mirrorProbes", probes)?;
+                array.set(&mut cx, i as u32, obj)?;
+            }
+            Ok(array)
+        });
+    });
+
+    Ok(promise)
+}
+
+fn fetch_manifest(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let url = cx.argument::<JsString>(0)?.value(&mut cx);
+
+    let (deferred, promise) = cx.promise();
+    let channel = cx.channel();
+
+    RUNTIME.spawn(async move {
+        let result = manifest::fetch(&MANIFEST_CLIENT, &url)
+            .await
+            .and_then(|manifest| Ok((manifest.files(&url)?, manifest)));
+        deferred.settle_with(&channel, move |mut cx| {
+            let (files, manifest) = match result {
+                Ok(result) => result,
+                Err(e) => {
+                    let error = cx.error(e.to_string())?;
+                    let code = cx.string(e.code());
+                    error.set(&mut cx, "code", code)?;
+                    return cx.throw(error);
+                }
+            };
+            manifest_to_js(&mut cx, &manifest, &files)
+        });
+    });
+
+    Ok(promise)
+}
+
+fn manifest_to_js<'a, C: Context<'a>>(
+    cx: &mut C,
+    manifest: &Manifest,
+    files: &[FileToDownload],
+) -> JsResult<'a, JsArray> {
+    let array = cx.empty_array();
+    for (i, (entry, file)) in manifest.entries.iter().zip(files).enumerate() {
+        let obj = cx.empty_object();
+        let url = cx.string(&file.url);
+        obj.set(cx, "url", url)?;
+        let path = cx.string(&file.path);
+        obj.set(cx, "path", path)?;
+        let hash = cx.string(&file.sha256_hash);
+        obj.set(cx, "sha256_hash", hash)?;
+        if let Some(size) = entry.size {
+            let size = cx.number(size as f64);
+            obj.set(cx, "size", size)?;
+        }
+        if let Some(mtime) = entry.mtime {
+            let mtime = cx.number(mtime as f64);
+            obj.set(cx, "mtime", mtime)?;
+        }
+        array.set(cx, i as u32, obj)?;
+    }
+    Ok(array)
+}
+
+fn probe_mirrors(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let files = parse_files(&mut cx, 0)?;
+    let config = parse_download_options(&mut cx, 1)?;
+
+    let (deferred, promise) = cx.promise();
+    let channel = cx.channel();
+
+    RUNTIME.spawn(async move {
+        let probes = DownloadManager::with_config(config)
+            .probe_mirrors(&files)
+            .await;
+        deferred.settle_with(&channel, move |mut cx| probes_to_js(&mut cx, &probes));
+    });
+
+    Ok(promise)
+}
+
+/// Probe results, fastest mirror first.
+fn probes_to_js<'a, C: Context<'a>>(cx: &mut C, probes: &[MirrorProbe]) -> JsResult<'a, JsArray> {
+    let ranking = rank(probes);
+    let array = cx.empty_array();
+    for (i, base) in ranking.iter().enumerate() {
+        let probe = match probes.iter().find(|probe| probe.base == *base) {
+            Some(probe) => probe,
+            None => continue,
+        };
+        let obj = cx.empty_object();
+        let mirror = cx.string(&probe.base);
+        obj.set(cx, "mirror", mirror)?;
+        let latency_ms = match probe.latency {
+            Some(latency) => cx
+                .number(latency.as_secs_f64() * 1000.0)
+                .upcast::<JsValue>(),
+            None => cx.null().upcast(),
+        };
+        obj.set(cx, "latencyMs", latency_ms)?;
+        let bytes_per_second = match probe.bytes_per_second {
+            Some(rate) => cx.number(rate).upcast::<JsValue>(),
+            None => cx.null().upcast(),
+        };
+        obj.set(cx, "bytesPerSecond", bytes_per_second)?;
+        let error = match &probe.error {
+            Some(error) => cx.string(error).upcast::<JsValue>(),
+            None => cx.null().upcast(),
+        };
+        obj.set(cx, "error", error)?;
+        array.set(cx, i as u32, obj)?;
+    }
+    Ok(array)
+}
+
+fn list_quarantine(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let destination = PathBuf::from(cx.argument::<JsString>(0)?.value(&mut cx));
 
-    let manager = DOWNLOAD_MANAGER.clone();
     let (deferred, promise) = cx.promise();
     let channel = cx.channel();
 
     RUNTIME.spawn(async move {
-        let result = manager.download(destination, files).await;
+        let result = run_blocking(move || cleanup::list_snapshots(&destination)).await;
         deferred.settle_with(&channel, move |mut cx| match result {
-            Ok(()) => Ok(cx.boolean(true)),
+            Ok(snapshots) => snapshots_to_js(&mut cx, &snapshots),
             Err(e) => cx.throw_error(e.to_string()),
         });
     });
@@ -56,41 +318,366 @@ fn start_download(mut cx: FunctionContext) -> JsResult<JsPromise> {
     Ok(promise)
 }
 
-fn stop_download(mut cx: FunctionContext) -> JsResult<JsUndefined> {
-    DOWNLOAD_MANAGER.cancel();
+fn snapshots_to_js<'a, C: Context<'a>>(
+    cx: &mut C,
+    snapshots: &[QuarantineSnapshot],
+) -> JsResult<'a, JsArray> {
+    let array = cx.empty_array();
+    for (i, snapshot) in snapshots.iter().enumerate() {
+        let obj = cx.empty_object();
+        let id = cx.string(&snapshot.id);
+        obj.set(cx, "id", id)?;
+        let created = cx.number(snapshot.created.timestamp_millis() as f64);
+        obj.set(cx, "createdAt", created)?;
+        let files = cx.empty_array();
+        for (j, (path, size)) in snapshot.files.iter().enumerate() {
+            let file = cx.empty_object();
+            let path = cx.string(path.to_string_lossy());
+            file.set(cx, "path", path)?;
+            let size = cx.number(*size as f64);
+            file.set(cx, "size", size)?;
+            files.set(cx, j as u32, file)?;
+        }
+        obj.set(cx, "files", files)?;
+        array.set(cx, i as u32, obj)?;
+    }
+    Ok(array)
+}
+
+fn restore_quarantine(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let destination = PathBuf::from(cx.argument::<JsString>(0)?.value(&mut cx));
+    let id = cx.argument::<JsString>(1)?.value(&mut cx);
+    let paths = parse_paths(&mut cx, 2)?;
+
+    let (deferred, promise) = cx.promise();
+    let channel = cx.channel();
+
+    RUNTIME.spawn(async move {
+        let result =
+            run_blocking(move || cleanup::restore(&destination, &id, paths.as_deref())).await;
+        deferred.settle_with(&channel, move |mut cx| match result {
+            Ok(restored) => {
+                let array = cx.empty_array();
+                for (i, path) in restored.iter().enumerate() {
+                    let path = cx.string(path.to_string_lossy());
+                    array.set(&mut cx, i as u32, path)?;
+                }
+                Ok(array)
+            }
+            Err(e) => cx.throw_error(e.to_string()),
+        });
+    });
+
+    Ok(promise)
+}
+
+fn preview_cleanup(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let destination = PathBuf::from(cx.argument::<JsString>(0)?.value(&mut cx));
+    let files = parse_files(&mut cx, 1)?;
+
+    // Mid-sync the folder is full of partial files and the preview would be stale
+    let lease = match JOBS.lease(&destination) {
+        Ok(lease) => lease,
+        Err(e) => return cx.throw_error(e.to_string()),
+    };
+
+    let (deferred, promise) = cx.promise();
+    let channel = cx.channel();
+
+    RUNTIME.spawn(async move {
+        let result = DownloadManager::new()
+            .preview_cleanup(&destination, &expected_files(&files))
+            .await;
+        drop(lease);
+        deferred.settle_with(&channel, move |mut cx| match result {
+            Ok(entries) => cleanup_entries_to_js(&mut cx, &entries),
+            Err(e) => cx.throw_error(e.to_string()),
+        });
+    });
+
+    Ok(promise)
+}
+
+fn cleanup_entries_to_js<'a, C: Context<'a>>(
+    cx: &mut C,
+    entries: &[CleanupEntry],
+) -> JsResult<'a, JsArray> {
+    let array = cx.empty_array();
+    for (i, entry) in entries.iter().enumerate() {
+        let obj = cx.empty_object();
+        let path = cx.string(entry.path.to_string_lossy());
+        obj.set(cx, "path", path)?;
+        let is_dir = cx.boolean(entry.is_dir);
+        obj.set(cx, "isDirectory", is_dir)?;
+        let size = cx.number(entry.size as f64);
+        obj.set(cx, "size", size)?;
+        let managed_dir = cx.string(entry.managed_dir.to_string_lossy());
+        obj.set(cx, "managedFolder", managed_dir)?;
+        array.set(cx, i as u32, obj)?;
+    }
+    Ok(array)
+}
+
+/// Removes the approved paths from a cleanup preview, or everything cleanup
+/// finds when no list is given. Resolves to the paths actually removed.
+fn run_cleanup(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let destination = PathBuf::from(cx.argument::<JsString>(0)?.value(&mut cx));
+    let files = parse_files(&mut cx, 1)?;
+    let approved = parse_paths(&mut cx, 2)?;
+    let config = parse_download_options(&mut cx, 3)?;
+
+    // Cleanup mustn't pull files out from under a sync into the same folder
+    let lease = match JOBS.lease(&destination) {
+        Ok(lease) => lease,
+        Err(e) => return cx.throw_error(e.to_string()),
+    };
+
+    let (deferred, promise) = cx.promise();
+    let channel = cx.channel();
+
+    RUNTIME.spawn(async move {
+        let manager = DownloadManager::with_config(config);
+        let expected = expected_files(&files);
+        let result = match approved {
+            Some(approved) => {
+                manager
+                    .apply_cleanup(&destination, &expected, &approved)
+                    .await
+            }
+            None => manager.cleanup_files(&destination, &expected).await,
+        };
+        drop(lease);
+        deferred.settle_with(&channel, move |mut cx| match result {
+            Ok(removed) => {
+                let array = cx.empty_array();
+                for (i, path) in removed.iter().enumerate() {
+                    let path = cx.string(path.to_string_lossy());
+                    array.set(&mut cx, i as u32, path)?;
+                }
+                Ok(array)
+            }
+            Err(e) => cx.throw_error(e.to_string()),
+        });
+    });
+
+    Ok(promise)
+}
+
+fn remove_job(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let id = cx.argument::<JsNumber>(0)?.value(&mut cx) as JobId;
+
+    if let Err(e) = JOBS.remove(id) {
+        return cx.throw_error(e.to_string());
+    }
 
     Ok(cx.undefined())
 }
 
-fn get_progress(mut cx: FunctionContext) -> JsResult<JsPromise> {
-    let manager = DOWNLOAD_MANAGER.clone();
+fn parse_download_options(cx: &mut FunctionContext, index: usize) -> NeonResult<DownloadConfig> {
+    let mut config = DownloadConfig::default();
+
+    let options = match cx.argument_opt(index) {
+        Some(value) => match value.downcast::<JsObject, _>(cx) {
+            Ok(options) => options,
+            Err(_) => return Ok(config),
+        },
+        None => return Ok(config),
+    };
+
+    if let Some(concurrency) = options.get_opt::<JsNumber, _, _>(cx, "concurrency")? {
+        config.max_concurrent_downloads = concurrency.value(cx).max(1.0) as usize;
+    }
+
+    if let Some(hash_cache) = options.get_opt::<JsBoolean, _, _>(cx, "hashCache")? {
+        config.use_hash_cache = hash_cache.value(cx);
+    }
+
+    if let Some(force_rehash) = options.get_opt::<JsBoolean, _, _>(cx, "forceRehash")? {
+        config.force_rehash = force_rehash.value(cx);
+    }
+
+    config.mirrors = parse_strings(cx, options, "mirrors")?;
+
+    if let Some(probe_mirrors) = options.get_opt::<JsBoolean, _, _>(cx, "probeMirrors")? {
+        config.probe_mirrors = probe_mirrors.value(cx);
+    }
+
+    // null turns segmenting off, leaving it out keeps the default threshold
+    if let Some(threshold) = options.get_opt::<JsValue, _, _>(cx, "segmentThreshold")? {
+        config.segment_threshold = match threshold.downcast::<JsNumber, _>(cx) {
+            Ok(threshold) => Some(threshold.value(cx).max(0.0) as u64),
+            Err(_) => None,
+        };
+    }
+
+    if let Some(segments) = options.get_opt::<JsNumber, _, _>(cx, "segments")? {
+        config.max_segments = segments.value(cx).max(1.0) as usize;
+    }
+
+    if let Some(across) = options.get_opt::<JsBoolean, _, _>(cx, "segmentAcrossMirrors")? {
+        config.segment_across_mirrors = across.value(cx);
+    }
+
+    if let Some(rate_limit) = options.get_opt::<JsObject, _, _>(cx, "rateLimit")? {
+        config.rate_limit = parse_rate_limit(cx, rate_limit)?;
+    }
+
+    if let Some(timeouts) = options.get_opt::<JsObject, _, _>(cx, "timeouts")? {
+        if let Some(timeout) = timeouts.get_opt::<JsNumber, _, _>(cx, "connectMs")? {
+            config.connect_timeout = Duration::from_millis(timeout.value(cx).max(1.0) as u64);
+        }
+        if let Some(timeout) = timeouts.get_opt::<JsNumber, _, _>(cx, "firstByteMs")? {
+            config.first_byte_timeout = Duration::from_millis(timeout.value(cx).max(1.0) as u64);
+        }
+        if let Some(timeout) = timeouts.get_opt::<JsNumber, _, _>(cx, "stallMs")? {
+            config.stall_timeout = Duration::from_millis(timeout.value(cx).max(1.0) as u64);
+        }
+    }
+
+    if let Some(auto_cleanup) = options.get_opt::<JsBoolean, _, _>(cx, "autoCleanup")? {
+        config.auto_cleanup = auto_cleanup.value(cx);
+    }
+
+    if let Some(policy) = options.get_opt::<JsString, _, _>(cx, "cleanupPolicy")? {
+        config.cleanup_policy = match policy.value(cx).parse() {
+            Ok(policy) => policy,
+            Err(e) => return cx.throw_error(e),
+        };
+    }
+
+    if let Some(days) = options.get_opt::<JsNumber, _, _>(cx, "quarantineRetentionDays")? {
+        // Cleanup treats a retention too long to represent as never expiring
+        config.quarantine_retention =
+            Duration::try_from_secs_f64(days.value(cx).max(0.0) * 24.0 * 60.0 * 60.0)
+                .unwrap_or(Duration::MAX);
+    }
+
+    if let Some(retry) = options.get_opt::<JsObject, _, _>(cx, "retry")? {
+        let policy = &mut config.retry_policy;
+        if let Some(max_attempts) = retry.get_opt::<JsNumber, _, _>(cx, "maxAttempts")? {
+            policy.max_attempts = max_attempts.value(cx).max(1.0) as u32;
+        }
+        if let Some(backoff) = retry.get_opt::<JsNumber, _, _>(cx, "initialBackoffMs")? {
+            policy.initial_backoff = Duration::from_millis(backoff.value(cx).max(0.0) as u64);
+        }
+        if let Some(backoff) = retry.get_opt::<JsNumber, _, _>(cx, "maxBackoffMs")? {
+            policy.max_backoff = Duration::from_millis(backoff.value(cx).max(0.0) as u64);
+        }
+    }
+
+    Ok(config)
+}
+
+/// Reads `{ bytesPerSecond?, schedule?: [{ from: "HH:MM", to: "HH:MM", bytesPerSecond? }] }`,
+/// where a missing or null `bytesPerSecond` means unlimited.
+fn parse_rate_limit(cx: &mut FunctionContext, obj: Handle<JsObject>) -> NeonResult<RateLimit> {
+    let mut limit = RateLimit {
+        bytes_per_second: parse_bytes_per_second(cx, obj)?,
+        schedule: Vec::new(),
+    };
+
+    if let Some(schedule) = obj.get_opt::<JsArray, _, _>(cx, "schedule")? {
+        for window in schedule.to_vec(cx)? {
+            let window = window.downcast_or_throw::<JsObject, _>(cx)?;
+            let from = window.get::<JsString, _, _>(cx, "from")?.value(cx);
+            let to = window.get::<JsString, _, _>(cx, "to")?.value(cx);
+            let (start_minute, end_minute) =
+                match (parse_time_of_day(&from), parse_time_of_day(&to)) {
+                    (Some(start), Some(end)) => (start, end),
+                    _ => return cx.throw_error(format!("Invalid schedule window {}-{}", from, to)),
+                };
+            limit.schedule.push(ScheduleWindow {
+                start_minute,
+                end_minute,
+                bytes_per_second: parse_bytes_per_second(cx, window)?,
+            });
+        }
+    }
+
+    Ok(limit)
+}
+
+fn parse_bytes_per_second(
+    cx: &mut FunctionContext,
+    obj: Handle<JsObject>,
+) -> NeonResult<Option<u64>> {
+    let rate = obj.get_opt::<JsValue, _, _>(cx, "bytesPerSecond")?;
+    match rate {
+        Some(rate) if !rate.is_a::<JsNull, _>(cx) => {
+            let rate = rate.downcast_or_throw::<JsNumber, _>(cx)?.value(cx);
+            Ok(Some(rate.max(1.0) as u64))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Minutes since midnight for a `HH:MM` time.
+fn parse_time_of_day(time: &str) -> Option<u32> {
+    let (hours, minutes) = time.split_once(':')?;
+    let hours: u32 = hours.trim().parse().ok()?;
+    let minutes: u32 = minutes.trim().parse().ok()?;
+    (hours < 24 && minutes < 60).then(|| hours * 60 + minutes)
+}
+
+fn set_rate_limit(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let limit = match cx.argument_opt(0) {
+        Some(value) if value.is_a::<JsObject, _>(&mut cx) => {
+            let obj = value.downcast_or_throw::<JsObject, _>(&mut cx)?;
+            parse_rate_limit(&mut cx, obj)?
+        }
+        _ => RateLimit::default(),
+    };
+
+    // Without a job id the limit is shared by every job
+    match parse_job_id(&mut cx, 1)? {
+        Some(id) => match JOBS.get(id) {
+            Ok(job) => job.manager.set_rate_limit(limit),
+            Err(e) => return cx.throw_error(e.to_string()),
+        },
+        None => JOBS.set_rate_limit(limit),
+    }
+
+    Ok(cx.undefined())
+}
+
+fn plan_download(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let destination = cx.argument::<JsString>(0)?.value(&mut cx);
+    let files = parse_files(&mut cx, 1)?;
 
     let (deferred, promise) = cx.promise();
     let channel = cx.channel();
 
     RUNTIME.spawn(async move {
-        let progress = manager.get_progress().await;
+        // Planning writes nothing but the hash index, and every save goes through
+        // its own temporary file, so it can run beside a job into the same folder
+        let result = DownloadManager::new().plan(destination, &files).await;
         deferred.settle_with(&channel, move |mut cx| {
+            let plan = match result {
+                Ok(plan) => plan,
+                Err(e) => return cx.throw_error(e.to_string()),
+            };
+
             let obj = cx.empty_object();
-            let status = cx.string(format!("{:?}", progress.status));
-            obj.set(&mut cx, "status", status)?;
-            let files_total = cx.number(progress.files_total as f64);
-            obj.set(&mut cx, "filesTotal", files_total)?;
-            let files_total_completed = cx.number(progress.files_total_completed as f64);
-            obj.set(&mut cx, "filesTotalCompleted", files_total_completed)?;
-            let verification_total_completed =
-                cx.number(progress.verification_total_completed as f64);
-            obj.set(
-                &mut cx,
-                "verificationTotalCompleted",
-                verification_total_completed,
-            )?;
-            let current_file_downloaded = cx.number(progress.current_file_downloaded as f64);
-            obj.set(&mut cx, "currentFileDownloaded", current_file_downloaded)?;
-            let current_file_total_size = cx.number(progress.current_file_total_size as f64);
-            obj.set(&mut cx, "currentFileTotalSize", current_file_total_size)?;
-            let current_file_path = cx.string(&progress.current_file_path);
-            obj.set(&mut cx, "currentFilePath", current_file_path)?;
+            let up_to_date = file_paths_to_js(&mut cx, &plan.up_to_date)?;
+            obj.set(&mut cx, "upToDate", up_to_date)?;
+            let missing = file_paths_to_js(&mut cx, &plan.missing)?;
+            obj.set(&mut cx, "missing", missing)?;
+            let mismatched = file_paths_to_js(&mut cx, &plan.mismatched)?;
+            obj.set(&mut cx, "mismatched", mismatched)?;
+            let extra = cx.empty_array();
+            for (i, path) in plan.extra.iter().enumerate() {
+                let path = cx.string(path.to_string_lossy());
+                extra.set(&mut cx, i as u32, path)?;
+            }
+            obj.set(&mut cx, "extra", extra)?;
+            let rejected = file_errors_to_js(&mut cx, &plan.rejected)?;
+            obj.set(&mut cx, "rejected", rejected)?;
+            let up_to_date_bytes = cx.number(plan.up_to_date_bytes as f64);
+            obj.set(&mut cx, "upToDateBytes", up_to_date_bytes)?;
+            let mismatched_bytes = cx.number(plan.mismatched_bytes as f64);
+            obj.set(&mut cx, "mismatchedBytes", mismatched_bytes)?;
+            let extra_bytes = cx.number(plan.extra_bytes as f64);
+            obj.set(&mut cx, "extraBytes", extra_bytes)?;
             Ok(obj)
         });
     });
@@ -98,6 +685,335 @@ fn get_progress(mut cx: FunctionContext) -> JsResult<JsPromise> {
     Ok(promise)
 }
 
+fn file_paths_to_js<'a, C: Context<'a>>(
+    cx: &mut C,
+    files: &[FileToDownload],
+) -> JsResult<'a, JsArray> {
+    let array = cx.empty_array();
+    for (i, file) in files.iter().enumerate() {
+        let path = cx.string(&file.path);
+        array.set(cx, i as u32, path)?;
+    }
+    Ok(array)
+}
+
+fn stop_download(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    for job in parse_jobs(&mut cx, 0)? {
+        job.manager.cancel();
+    }
+
+    Ok(cx.undefined())
+}
+
+fn pause_download(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let jobs = parse_jobs(&mut cx, 0)?;
+
+    let (deferred, promise) = cx.promise();
+    let channel = cx.channel();
+
+    RUNTIME.spawn(async move {
+        let mut paused = false;
+        for job in jobs {
+            paused |= job.manager.pause().await;
+        }
+        deferred.settle_with(&channel, move |mut cx| Ok(cx.boolean(paused)));
+    });
+
+    Ok(promise)
+}
+
+fn resume_download(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let jobs = parse_jobs(&mut cx, 0)?;
+
+    let (deferred, promise) = cx.promise();
+    let channel = cx.channel();
+
+    RUNTIME.spawn(async move {
+        let mut resumed = false;
+        for job in jobs {
+            resumed |= job.manager.resume().await;
+        }
+        deferred.settle_with(&channel, move |mut cx| Ok(cx.boolean(resumed)));
+    });
+
+    Ok(promise)
+}
+
+fn get_progress(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    // Without an id this reports the most recent job, as the single manager used to
+    let job = match parse_job_id(&mut cx, 0)? {
+        Some(id) => match JOBS.get(id) {
+            Ok(job) => Some(job),
+            Err(e) => return cx.throw_error(e.to_string()),
+        },
+        None => JOBS.latest(),
+    };
+
+    let (deferred, promise) = cx.promise();
+    let channel = cx.channel();
+
+    RUNTIME.spawn(async move {
+        let progress = match job {
+            Some(job) => job.manager.get_progress().await,
+            None => DownloadProgress::default(),
+        };
+        deferred.settle_with(&channel, move |mut cx| progress_to_js(&mut cx, &progress));
+    });
+
+    Ok(promise)
+}
+
+fn progress_to_js<'a, C: Context<'a>>(
+    cx: &mut C,
+    progress: &DownloadProgress,
+) -> JsResult<'a, JsObject> {
+    let obj = cx.empty_object();
+    let status = cx.string(format!("{:?}", progress.status));
+    obj.set(cx, "status", status)?;
+    let files_total = cx.number(progress.files_total as f64);
+    obj.set(cx, "filesTotal", files_total)?;
+    let files_total_completed = cx.number(progress.files_total_completed as f64);
+    obj.set(cx, "filesTotalCompleted", files_total_completed)?;
+    let verification_total_completed = cx.number(progress.verification_total_completed as f64);
+    obj.set(
+        cx,
+        "verificationTotalCompleted",
+        verification_total_completed,
+    )?;
+    let current_file_downloaded = cx.number(progress.current_file_downloaded as f64);
+    obj.set(cx, "currentFileDownloaded", current_file_downloaded)?;
+    let current_file_total_size = cx.number(progress.current_file_total_size as f64);
+    obj.set(cx, "currentFileTotalSize", current_file_total_size)?;
+    let current_file_path = cx.string(&progress.current_file_path);
+    obj.set(cx, "currentFilePath", current_file_path)?;
+    let active_files = cx.empty_array();
+    for (i, (path, file_progress)) in progress.active_files.iter().enumerate() {
+        let file_obj = cx.empty_object();
+        let path = cx.string(path);
+        file_obj.set(cx, "path", path)?;
+        let downloaded = cx.number(file_progress.downloaded as f64);
+        file_obj.set(cx, "downloaded", downloaded)?;
+        let total_size = cx.number(file_progress.total_size as f64);
+        file_obj.set(cx, "totalSize", total_size)?;
+        let attempt = cx.number(file_progress.attempt);
+        file_obj.set(cx, "attempt", attempt)?;
+        let max_attempts = cx.number(file_progress.max_attempts);
+        file_obj.set(cx, "maxAttempts", max_attempts)?;
+        active_files.set(cx, i as u32, file_obj)?;
+    }
+    obj.set(cx, "activeFiles", active_files)?;
+    let scan_files_total = cx.number(progress.scan_files_total as f64);
+    obj.set(cx, "scanFilesTotal", scan_files_total)?;
+    let scan_files_completed = cx.number(progress.scan_files_completed as f64);
+    obj.set(cx, "scanFilesCompleted", scan_files_completed)?;
+    let scan_bytes_completed = cx.number(progress.scan_bytes_completed as f64);
+    obj.set(cx, "scanBytesCompleted", scan_bytes_completed)?;
+    let bytes_transferred = cx.number(progress.bytes_transferred as f64);
+    obj.set(cx, "bytesTransferred", bytes_transferred)?;
+    let bytes_total = cx.number(progress.bytes_total as f64);
+    obj.set(cx, "bytesTotal", bytes_total)?;
+    let bytes_completed = cx.number(progress.bytes_completed as f64);
+    obj.set(cx, "bytesCompleted", bytes_completed)?;
+    let throughput = cx.number(progress.throughput);
+    obj.set(cx, "throughput", throughput)?;
+    let eta_seconds = match progress.eta_seconds {
+        Some(eta) => cx.number(eta).upcast::<JsValue>(),
+        None => cx.null().upcast(),
+    };
+    obj.set(cx, "etaSeconds", eta_seconds)?;
+    let mut failed_files: Vec<&FileError> = progress.failed_files.values().collect();
+    failed_files.sort_by(|a, b| a.path.cmp(&b.path));
+    let failed_files = file_errors_to_js(cx, failed_files)?;
+    obj.set(cx, "failedFiles", failed_files)?;
+    Ok(obj)
+}
+
+fn summary_to_js<'a, C: Context<'a>>(cx: &mut C, summary: &SyncSummary) -> JsResult<'a, JsObject> {
+    let obj = cx.empty_object();
+    let downloaded = cx.number(summary.downloaded as f64);
+    obj.set(cx, "downloaded", downloaded)?;
+    let skipped = cx.number(summary.skipped as f64);
+    obj.set(cx, "skipped", skipped)?;
+    let failed = cx.number(summary.failed as f64);
+    obj.set(cx, "failed", failed)?;
+    let removed = cx.number(summary.removed as f64);
+    obj.set(cx, "removed", removed)?;
+    let bytes_transferred = cx.number(summary.bytes_transferred as f64);
+    obj.set(cx, "bytesTransferred", bytes_transferred)?;
+    let duration_ms = cx.number(summary.duration.as_millis() as f64);
+    obj.set(cx, "durationMs", duration_ms)?;
+    let errors = file_errors_to_js(cx, &summary.errors)?;
+    obj.set(cx, "errors", errors)?;
+    let served_by = cx.empty_object();
+    for (path, mirror) in &summary.served_by {
+        let mirror = cx.string(mirror);
+        served_by.set(cx, path.as_str(), mirror)?;
+    }
+    obj.set(cx, "servedBy", served_by)?;
+    Ok(obj)
+}
+
+fn file_errors_to_js<'a, 'b, C: Context<'a>>(
+    cx: &mut C,
+    errors: impl IntoIterator<Item = &'b FileError>,
+) -> JsResult<'a, JsArray> {
+    let array = cx.empty_array();
+    for (i, error) in errors.into_iter().enumerate() {
+        let obj = cx.empty_object();
+        let path = cx.string(&error.path);
+        obj.set(cx, "path", path)?;
+        let code = cx.string(error.code);
+        obj.set(cx, "code", code)?;
+        let message = cx.string(&error.message);
+        obj.set(cx, "message", message)?;
+        array.set(cx, i as u32, obj)?;
+    }
+    Ok(array)
+}
+
+/// Calls `callback` with discrete download events as they happen, and with
+/// progress snapshots at most once per `intervalMs` while anything changes.
+/// Returns an id for `unsubscribe_progress`.
+fn subscribe_progress(mut cx: FunctionContext) -> JsResult<JsNumber> {
+    let callback = Arc::new(cx.argument::<JsFunction>(0)?.root(&mut cx));
+    let interval_ms = match cx.argument_opt(1) {
+        Some(value) => match value.downcast::<JsNumber, _>(&mut cx) {
+            Ok(interval) => interval.value(&mut cx).max(16.0),
+            Err(_) => 250.0,
+        },
+        None => 250.0,
+    };
+
+    let mut events = JOBS.subscribe();
+    let channel = cx.channel();
+
+    let handle = RUNTIME.spawn(async move {
+        let mut ticker = tokio::time::interval(Duration::from_millis(interval_ms as u64));
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        // Jobs that need a snapshot even though they're no longer active
+        let mut changed: HashSet<JobId> = JOBS.list().iter().map(|job| job.id).collect();
+
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {
+                    for job in JOBS.list() {
+                        let progress = job.manager.get_progress().await;
+                        if !changed.remove(&job.id) && !is_active(progress.status) {
+                            continue;
+                        }
+
+                        let id = job.id;
+                        send_to_callback(&channel, &callback, move |cx| {
+                            let obj = cx.empty_object();
+                            let kind = cx.string("progress");
+                            obj.set(cx, "type", kind)?;
+                            let id = cx.number(id);
+                            obj.set(cx, "jobId", id)?;
+                            let progress = progress_to_js(cx, &progress)?;
+                            obj.set(cx, "progress", progress)?;
+                            Ok(obj)
+                        });
+                    }
+                }
+                event = events.recv() => match event {
+                    Ok((id, event)) => {
+                        changed.insert(id);
+                        send_to_callback(&channel, &callback, move |cx| {
+                            let obj = event_to_js(cx, &event)?;
+                            let id = cx.number(id);
+                            obj.set(cx, "jobId", id)?;
+                            Ok(obj)
+                        });
+                    }
+                    Err(RecvError::Lagged(_)) => {
+                        changed.extend(JOBS.list().iter().map(|job| job.id));
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+            }
+        }
+    });
+
+    let id = NEXT_SUBSCRIPTION_ID.fetch_add(1, Ordering::SeqCst);
+    SUBSCRIPTIONS.lock().unwrap().insert(id, handle);
+
+    Ok(cx.number(id))
+}
+
+fn unsubscribe_progress(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let id = cx.argument::<JsNumber>(0)?.value(&mut cx) as u32;
+
+    if let Some(handle) = SUBSCRIPTIONS.lock().unwrap().remove(&id) {
+        handle.abort();
+    }
+
+    Ok(cx.undefined())
+}
+
+fn is_active(status: DownloadStatus) -> bool {
+    matches!(
+        status,
+        DownloadStatus::Initiating
+            | DownloadStatus::Scanning
+            | DownloadStatus::Downloading
+            | DownloadStatus::Paused
+    )
+}
+
+fn send_to_callback<F>(channel: &Channel, callback: &Arc<Root<JsFunction>>, build: F)
+where
+    F: for<'a> FnOnce(&mut TaskContext<'a>) -> JsResult<'a, JsObject> + Send + 'static,
+{
+    let callback = callback.clone();
+    channel.send(move |mut cx| {
+        let payload = build(&mut cx)?;
+        let callback = callback.to_inner(&mut cx);
+        callback.call_with(&cx).arg(payload).exec(&mut cx)
+    });
+}
+
+fn event_to_js<'a, C: Context<'a>>(cx: &mut C, event: &DownloadEvent) -> JsResult<'a, JsObject> {
+    let obj = cx.empty_object();
+
+    let (kind, path) = match event {
+        DownloadEvent::FileStarted { path } => ("fileStarted", Some(path.clone())),
+        DownloadEvent::VerificationStarted { path } => ("verificationStarted", Some(path.clone())),
+        DownloadEvent::FileCompleted { path } => ("fileCompleted", Some(path.clone())),
+        DownloadEvent::FileFailed { path, .. } => ("fileFailed", Some(path.clone())),
+        DownloadEvent::CleanupRemoved { path } => {
+            ("cleanupRemoved", Some(path.to_string_lossy().into_owned()))
+        }
+        DownloadEvent::HashIndexFailed { .. } => ("hashIndexFailed", None),
+        DownloadEvent::SyncFinished { .. } => ("syncFinished", None),
+    };
+
+    let kind = cx.string(kind);
+    obj.set(cx, "type", kind)?;
+
+    if let Some(path) = path {
+        let path = cx.string(path);
+        obj.set(cx, "path", path)?;
+    }
+
+    match event {
+        DownloadEvent::FileFailed { error, .. } | DownloadEvent::HashIndexFailed { error } => {
+            let error = cx.string(error);
+            obj.set(cx, "error", error)?;
+        }
+        DownloadEvent::SyncFinished { status, error } => {
+            let status = cx.string(format!("{:?}", status));
+            obj.set(cx, "status", status)?;
+            if let Some(error) = error {
+                let error = cx.string(error);
+                obj.set(cx, "error", error)?;
+            }
+        }
+        _ => {}
+    }
+
+    Ok(obj)
+}
+
 fn ping(mut cx: FunctionContext) -> JsResult<JsString> {
     Ok(cx.string("pong"))
 }
@@ -105,8 +1021,23 @@ fn ping(mut cx: FunctionContext) -> JsResult<JsString> {
 #[neon::main]
 fn main(mut cx: ModuleContext) -> NeonResult<()> {
     cx.export_function("start_download", start_download)?;
+    cx.export_function("wait_for_job", wait_for_job)?;
+    cx.export_function("list_jobs", list_jobs)?;
+    cx.export_function("remove_job", remove_job)?;
+    cx.export_function("list_quarantine", list_quarantine)?;
+    cx.export_function("restore_quarantine", restore_quarantine)?;
+    cx.export_function("preview_cleanup", preview_cleanup)?;
+    cx.export_function("run_cleanup", run_cleanup)?;
+    cx.export_function("plan_download", plan_download)?;
+    cx.export_function("fetch_manifest", fetch_manifest)?;
+    cx.export_function("probe_mirrors", probe_mirrors)?;
     cx.export_function("stop_download", stop_download)?;
+    cx.export_function("pause_download", pause_download)?;
+    cx.export_function("set_rate_limit", set_rate_limit)?;
+    cx.export_function("resume_download", resume_download)?;
     cx.export_function("get_progress", get_progress)?;
+    cx.export_function("subscribe_progress", subscribe_progress)?;
+    cx.export_function("unsubscribe_progress", unsubscribe_progress)?;
     cx.export_function("ping", ping)?;
     Ok(())
 }
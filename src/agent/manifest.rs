@@ -0,0 +1,385 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+use futures::{stream, StreamExt};
+use reqwest::{Client, Url};
+use thiserror::Error;
+use walkdir::WalkDir;
+
+use crate::cleanup::QUARANTINE_DIR_NAME;
+use crate::download::{
+    is_sync_artifact, relative_path, run_blocking, sha256_file, DownloadError, FileToDownload,
+};
+
+/// A manifest server that stops answering fails the fetch instead of hanging it.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Error)]
+pub enum ManifestError {
+    #[error("HTTP error: {0}")]
+    HttpError(#[from] reqwest::Error),
+    #[error("Manifest request failed ({status}): {url}")]
+    BadStatus { status: u16, url: String },
+    #[error("Malformed manifest XML at line {line}: {message}")]
+    Malformed { line: usize, message: String },
+    #[error("Manifest root must be <theupdates>, found <{0}>")]
+    UnexpectedRoot(String),
+    #[error("File entry {index} is missing its {field}")]
+    MissingField { index: usize, field: &'static str },
+    #[error("File entry {index} has an invalid {field}: {value:?}")]
+    InvalidValue {
+        index: usize,
+        field: &'static str,
+        value: String,
+    },
+    #[error("{0} is listed more than once")]
+    DuplicatePath(String),
+    #[error("Invalid manifest URL: {0}")]
+    InvalidUrl(String),
+}
+
+impl ManifestError {
+    /// Machine-readable identifier, in the style of `DownloadError::code`.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ManifestError::HttpError(_) => "http_error",
+            ManifestError::BadStatus { .. } => "bad_status",
+            ManifestError::Malformed { .. } => "malformed",
+            ManifestError::UnexpectedRoot(_) => "unexpected_root",
+            ManifestError::MissingField { .. } => "missing_field",
+            ManifestError::InvalidValue { .. } => "invalid_value",
+            ManifestError::DuplicatePath(_) => "duplicate_path",
+            ManifestError::InvalidUrl(_) => "invalid_url",
+        }
+    }
+}
+
+/// One `<file>` of a manifest.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ManifestEntry {
+    /// Path relative to the repository root, as written in the manifest.
+    pub path: String,
+    pub sha256_hash: String,
+    pub size: Option<u64>,
+    /// Modification time in seconds since the Unix epoch.
+    pub mtime: Option<u64>,
+}
+
+/// A parsed `theupdates` repository manifest.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Manifest {
+    pub entries: Vec<ManifestEntry>,
+}
+
+impl Manifest {
+    /// Parses a manifest. Each `<file>` may give `name`, `hash`, `size` and
+    /// `mtime` either as attributes or as child elements.
+    pub fn parse(xml: &str) -> Result<Self, ManifestError> {
+        let options = roxmltree::ParsingOptions {
+            // Older generators wrote a doctype, entities in it stay bounded
+            allow_dtd: true,
+            ..Default::default()
+        };
+        let document = roxmltree::Document::parse_with_options(xml, options).map_err(malformed)?;
+        let root = document.root_element();
+        if !root.has_tag_name("theupdates") {
+            return Err(ManifestError::UnexpectedRoot(
+                root.tag_name().name().to_string(),
+            ));
+        }
+
+        let mut entries = Vec::new();
+        let mut seen = HashSet::new();
+        for (index, file) in root
+            .children()
+            .filter(|node| node.has_tag_name("file"))
+            .enumerate()
+        {
+            let path = field(file, "name").filter(|name| !name.is_empty()).ok_or(
+                ManifestError::MissingField {
+                    index,
+                    field: "name",
+                },
+            )?;
+            let hash = field(file, "hash").ok_or(ManifestError::MissingField {
+                index,
+                field: "hash",
+            })?;
+            if hash.len() != 64 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return Err(ManifestError::InvalidValue {
+                    index,
+                    field: "hash",
+                    value: hash,
+                });
+            }
+
+            let number = |name: &'static str| match field(file, name) {
+                Some(value) => value
+                    .parse()
+                    .map(Some)
+                    .map_err(|_| ManifestError::InvalidValue {
+                        index,
+                        field: name,
+                        value,
+                    }),
+                None => Ok(None),
+            };
+            let size = number("size")?;
+            let mtime = number("mtime")?;
+
+            if !seen.insert(path.trim_start_matches('/').to_string()) {
+                return Err(ManifestError::DuplicatePath(path));
+            }
+
+            entries.push(ManifestEntry {
+                path,
+                sha256_hash: hash.to_ascii_lowercase(),
+                size,
+                mtime,
+            });
+        }
+
+        Ok(Self { entries })
+    }
+
+    /// Files to download, with URLs resolved against the manifest's own
+    /// location so they land next to it on the server.
+    pub fn files(&self, manifest_url: &str) -> Result<Vec<FileToDownload>, ManifestError> {
+        let base =
+            Url::parse(manifest_url).map_err(|_| ManifestError::InvalidUrl(manifest_url.into()))?;
+
+        self.entries
+            .iter()
+            .map(|entry| {
+                let url = file_url(&base, &entry.path)
+                    .ok_or_else(|| ManifestError::InvalidUrl(entry.path.clone()))?;
+                Ok(FileToDownload {
+                    url: url.to_string(),
+                    path: entry.path.clone(),
+                    sha256_hash: entry.sha256_hash.clone(),
+                    size: entry.size,
+                    mirrors: Vec::new(),
+                })
+            })
+            .collect()
+    }
+
+    /// Writes the manifest with every field as a child element, which is the
+    /// form older clients read.
+    pub fn to_xml(&self) -> String {
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<theupdates>\n");
+        for entry in &self.entries {
+            xml.push_str("    <file>\n");
+            xml.push_str(&format!("        <name>{}</name>\n", escape(&entry.path)));
+            xml.push_str(&format!("        <hash>{}</hash>\n", entry.sha256_hash));
+            if let Some(size) = entry.size {
+                xml.push_str(&format!("        <size>{}</size>\n", size));
+            }
+            if let Some(mtime) = entry.mtime {
+                xml.push_str(&format!("        <mtime>{}</mtime>\n", mtime));
+            }
+            xml.push_str("    </file>\n");
+        }
+        xml.push_str("</theupdates>\n");
+        xml
+    }
+}
+
+/// Resolves the manifest path `path` against `base` the way `Url::join` would,
+/// except that every segment is percent-encoded, so `#`, `?` and spaces stay
+/// part of the file name. `None` if `base` can't have a path.
+pub(crate) fn file_url(base: &Url, path: &str) -> Option<Url> {
+    let mut url = base.clone();
+    url.set_query(None);
+    url.set_fragment(None);
+    {
+        let mut segments = url.path_segments_mut().ok()?;
+        // The last segment is the manifest itself, or empty after a trailing slash
+        segments.pop();
+        segments.extend(path.split('/').filter(|segment| !segment.is_empty()));
+    }
+    Some(url)
+}
+
+#[derive(Debug, Clone)]
+pub struct GeneratorConfig {
+    /// Files hashed at the same time.
+    pub concurrency: usize,
+    /// A manifest generated earlier. Its hashes are reused for files whose
+    /// size and mtime haven't changed.
+    pub previous: Option<Manifest>,
+    /// Paths relative to the folder to leave out, such as the manifest itself.
+    pub exclude: Vec<PathBuf>,
+}
+
+impl Default for GeneratorConfig {
+    fn default() -> Self {
+        Self {
+            concurrency: std::thread::available_parallelism().map_or(4, |n| n.get()),
+            previous: None,
+            exclude: Vec::new(),
+        }
+    }
+}
+
+/// Builds a manifest of every file under `folder`, as a client syncing into
+/// that folder would see it. Scarlet's own partial downloads and hash index
+/// are skipped so a repository can be published from a synced copy.
+pub async fn generate(folder: &Path, config: GeneratorConfig) -> std::io::Result<Manifest> {
+    let root = folder.to_path_buf();
+    let exclude = config.exclude.clone();
+    let mut files = run_blocking(move || list_files(&root, &exclude)).await?;
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+
+    let previous: HashMap<&str, &ManifestEntry> = config
+        .previous
+        .iter()
+        .flat_map(|manifest| &manifest.entries)
+        .map(|entry| (entry.path.trim_start_matches('/'), entry))
+        .collect();
+
+    let entries = stream::iter(files)
+        .map(|mut entry| {
+            let reusable = previous
+                .get(entry.path.trim_start_matches('/'))
+                .filter(|old| {
+                    old.size.is_some() && old.size == entry.size && old.mtime == entry.mtime
+                })
+                .map(|old| old.sha256_hash.clone());
+            let full_path = folder.join(entry.path.trim_start_matches('/'));
+            async move {
+                entry.sha256_hash = match reusable {
+                    Some(hash) => hash,
+                    None => run_blocking(move || sha256_file(&full_path)).await?,
+                };
+                Ok(entry)
+            }
+        })
+        .buffered(config.concurrency.max(1))
+        .collect::<Vec<std::io::Result<ManifestEntry>>>()
+        .await
+        .into_iter()
+        .collect::<std::io::Result<Vec<_>>>()?;
+
+    Ok(Manifest { entries })
+}
+
+/// Every file under `root` with its size and mtime, but no hash yet.
+fn list_files(root: &Path, exclude: &[PathBuf]) -> std::io::Result<Vec<ManifestEntry>> {
+    let mut files = Vec::new();
+
+    for entry in WalkDir::new(root) {
+        let entry = entry?;
+        if !entry.file_type().is_file() || is_sync_artifact(entry.path()) {
+            continue;
+        }
+        let relative = entry.path().strip_prefix(root).unwrap_or(entry.path());
+        if relative.starts_with(QUARANTINE_DIR_NAME) || exclude.iter().any(|path| relative == path)
+        {
+            continue;
+        }
+
+        let metadata = entry.metadata()?;
+        let mtime = metadata
+            .modified()
+            .ok()
+            .and_then(|mtime| mtime.duration_since(UNIX_EPOCH).ok())
+            .map(|mtime| mtime.as_secs());
+
+        files.push(ManifestEntry {
+            path: manifest_path(relative)?,
+            sha256_hash: String::new(),
+            size: Some(metadata.len()),
+            mtime,
+        });
+    }
+
+    Ok(files)
+}
+
+/// `relative` in the manifest's form: rooted, with forward slashes. Fails for
+/// a name clients would refuse to write, like `nul.txt` or `notes.`.
+fn manifest_path(relative: &Path) -> std::io::Result<String> {
+    let mut path = String::new();
+    for component in relative.components() {
+        match component {
+            Component::Normal(name) => {
+                let name = name.to_str().ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("{:?} is not valid UTF-8", relative),
+                    )
+                })?;
+                path.push('/');
+                path.push_str(name);
+            }
+            _ => continue,
+        }
+    }
+
+    match relative_path(&path) {
+        Ok(_) => Ok(path),
+        Err(DownloadError::UnsafePath { reason, .. }) => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("{} can't be published: {}", path, reason),
+        )),
+        Err(e) => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, e)),
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// HTTP client with the timeouts manifest requests should use.
+pub fn client() -> Client {
+    // Falls back to default settings only if the TLS backend can't initialise
+    Client::builder()
+        .connect_timeout(CONNECT_TIMEOUT)
+        .read_timeout(READ_TIMEOUT)
+        .build()
+        .unwrap_or_default()
+}
+
+/// Downloads and parses the manifest at `url`.
+pub async fn fetch(client: &Client, url: &str) -> Result<Manifest, ManifestError> {
+    let response = client.get(url).send().await?;
+    if !response.status().is_success() {
+        return Err(ManifestError::BadStatus {
+            status: response.status().as_u16(),
+            url: url.to_string(),
+        });
+    }
+    Manifest::parse(&response.text().await?)
+}
+
+/// An attribute of `element`, or failing that the text of a child element.
+fn field(element: roxmltree::Node, name: &str) -> Option<String> {
+    element
+        .attribute(name)
+        .map(|value| value.trim().to_string())
+        .or_else(|| {
+            element
+                .children()
+                .find(|child| child.has_tag_name(name))
+                .map(|child| {
+                    child
+                        .children()
+                        .filter_map(|node| node.text())
+                        .collect::<String>()
+                        .trim()
+                        .to_string()
+                })
+        })
+}
+
+fn malformed(e: roxmltree::Error) -> ManifestError {
+    ManifestError::Malformed {
+        line: e.pos().row as usize,
+        message: e.to_string(),
+    }
+}
@@ -0,0 +1,246 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use futures::StreamExt;
+use reqwest::header::RANGE;
+use reqwest::{Client, Url};
+
+use crate::download::FileToDownload;
+use crate::manifest::file_url;
+
+/// Failures in a row before a mirror is skipped for the rest of the job.
+const BLACKLIST_AFTER_FAILURES: u32 = 3;
+const BLACKLIST_DURATION: Duration = Duration::from_secs(60);
+
+/// One place a file can be fetched from. `base` identifies the mirror for
+/// health tracking and is what gets reported as having served the file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Source {
+    pub base: String,
+    pub url: String,
+}
+
+/// Where `file` can be downloaded from, in priority order: its own URL, then
+/// its mirrors, then the mirrors configured for the whole repository.
+pub fn sources_for(file: &FileToDownload, repository_mirrors: &[String]) -> Vec<Source> {
+    let relative_path = file.path.trim_start_matches('/');
+
+    let mut sources = vec![Source {
+        base: primary_base(&file.url, relative_path),
+        url: file.url.clone(),
+    }];
+
+    for base in file.mirrors.iter().chain(repository_mirrors) {
+        if let Some(url) = mirror_url(base, relative_path) {
+            if !sources.iter().any(|source| source.url == url) {
+                sources.push(Source {
+                    base: base.clone(),
+                    url,
+                });
+            }
+        }
+    }
+
+    sources
+}
+
+/// The file's URL on a mirror, or `None` if the base isn't a valid URL.
+fn mirror_url(base: &str, relative_path: &str) -> Option<String> {
+    // Without the trailing slash the base's last segment would be replaced
+    let base = if base.ends_with('/') {
+        Url::parse(base)
+    } else {
+        Url::parse(&format!("{}/", base))
+    };
+    file_url(&base.ok()?, relative_path).map(|url| url.to_string())
+}
+
+/// The base that `url` was built from by appending `relative_path`, or the
+/// URL's origin when it doesn't end in that path.
+fn primary_base(url: &str, relative_path: &str) -> String {
+    let parsed = match Url::parse(url) {
+        Ok(parsed) => parsed,
+        Err(_) => return url.to_string(),
+    };
+
+    let depth = relative_path.split('/').filter(|s| !s.is_empty()).count();
+    let mut base = parsed.clone();
+    if let Ok(mut segments) = base.path_segments_mut() {
+        for _ in 0..depth {
+            segments.pop();
+        }
+        segments.push("");
+    }
+
+    // Compared encoded, as the path may hold characters URLs escape
+    match file_url(&base, relative_path) {
+        Some(rebuilt) if depth > 0 && rebuilt == parsed => base.to_string(),
+        _ => format!("{}/", parsed.origin().ascii_serialization()),
+    }
+}
+
+#[derive(Debug, Default)]
+struct MirrorState {
+    consecutive_failures: u32,
+    blacklisted_until: Option<Instant>,
+}
+
+/// Tracks which mirrors keep failing during a job so other files stop trying them.
+#[derive(Debug, Default)]
+pub struct MirrorHealth {
+    mirrors: HashMap<String, MirrorState>,
+}
+
+impl MirrorHealth {
+    pub fn record_success(&mut self, base: &str) {
+        self.mirrors.remove(base);
+    }
+
+    pub fn record_failure(&mut self, base: &str) {
+        let state = self.mirrors.entry(base.to_string()).or_default();
+        state.consecutive_failures += 1;
+        if state.consecutive_failures >= BLACKLIST_AFTER_FAILURES {
+            state.blacklisted_until = Some(Instant::now() + BLACKLIST_DURATION);
+        }
+    }
+
+    pub fn is_blacklisted(&self, base: &str) -> bool {
+        self.mirrors
+            .get(base)
+            .and_then(|state| state.blacklisted_until)
+            .is_some_and(|until| Instant::now() < until)
+    }
+
+    /// The first source not yet tried for this file, preferring mirrors that
+    /// aren't blacklisted. Blacklisted mirrors are still used as a last resort.
+    pub fn choose<'a>(
+        &self,
+        sources: &'a [Source],
+        tried: &[usize],
+    ) -> Option<(usize, &'a Source)> {
+        let untried = || {
+            sources
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| !tried.contains(i))
+        };
+
+        untried()
+            .find(|(_, source)| !self.is_blacklisted(&source.base))
+            .or_else(|| untried().next())
+    }
+}
+
+/// Bytes fetched from each mirror to estimate its throughput.
+const PROBE_SAMPLE_BYTES: u64 = 256 * 1024;
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How a mirror performed when probed with a sample of one of its files.
+#[derive(Debug, Clone)]
+pub struct MirrorProbe {
+    pub base: String,
+    /// Time until response headers arrived.
+    pub latency: Option<Duration>,
+    pub bytes_per_second: Option<f64>,
+    pub error: Option<String>,
+}
+
+impl MirrorProbe {
+    /// Estimated seconds to fetch a megabyte, lower is better. `None` for a
+    /// mirror whose probe failed, even if it answered quickly at first.
+    fn cost(&self) -> Option<f64> {
+        if self.error.is_some() {
+            return None;
+        }
+        let latency = self.latency?.as_secs_f64();
+        match self.bytes_per_second {
+            Some(rate) if rate > 0.0 => Some(latency + 1_048_576.0 / rate),
+            _ => Some(latency),
+        }
+    }
+}
+
+/// Every distinct mirror the files can be fetched from, each with the URL of
+/// one of its files to sample.
+pub fn probe_targets(files: &[FileToDownload], repository_mirrors: &[String]) -> Vec<Source> {
+    let mut targets: Vec<Source> = Vec::new();
+    for file in files {
+        for source in sources_for(file, repository_mirrors) {
+            if !targets.iter().any(|target| target.base == source.base) {
+                targets.push(source);
+            }
+        }
+    }
+    targets
+}
+
+pub async fn probe(client: &Client, target: &Source) -> MirrorProbe {
+    let mut result = MirrorProbe {
+        base: target.base.clone(),
+        latency: None,
+        bytes_per_second: None,
+        error: None,
+    };
+
+    let started = Instant::now();
+    let response = client
+        .get(&target.url)
+        .header(RANGE, format!("bytes=0-{}", PROBE_SAMPLE_BYTES - 1))
+        .timeout(PROBE_TIMEOUT)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status());
+    let response = match response {
+        Ok(response) => response,
+        Err(e) => {
+            result.error = Some(e.to_string());
+            return result;
+        }
+    };
+    result.latency = Some(started.elapsed());
+
+    // Servers that ignore the range send the whole file, so stop at the sample size
+    let sample_started = Instant::now();
+    let mut received = 0;
+    let mut stream = response.bytes_stream();
+    while received < PROBE_SAMPLE_BYTES {
+        match stream.next().await {
+            Some(Ok(chunk)) => received += chunk.len() as u64,
+            Some(Err(e)) => {
+                result.error = Some(e.to_string());
+                return result;
+            }
+            None => break,
+        }
+    }
+
+    let elapsed = sample_started.elapsed().as_secs_f64();
+    if received > 0 && elapsed > 0.0 {
+        result.bytes_per_second = Some(received as f64 / elapsed);
+    }
+
+    result
+}
+
+/// Mirror bases ordered fastest first, with mirrors that failed the probe last.
+pub fn rank(probes: &[MirrorProbe]) -> Vec<String> {
+    let mut ranked: Vec<&MirrorProbe> = probes.iter().collect();
+    ranked.sort_by(|a, b| match (a.cost(), b.cost()) {
+        (Some(a), Some(b)) => a.total_cmp(&b),
+        (Some(_), None) => std::cmp::Ordering::Less,
+        (None, Some(_)) => std::cmp::Ordering::Greater,
+        (None, None) => std::cmp::Ordering::Equal,
+    });
+    ranked.into_iter().map(|probe| probe.base.clone()).collect()
+}
+
+/// Reorders `sources` to follow `ranking`, keeping unranked sources in their
+/// original order after the ranked ones.
+pub fn apply_ranking(sources: &mut [Source], ranking: &[String]) {
+    sources.sort_by_key(|source| {
+        ranking
+            .iter()
+            .position(|base| *base == source.base)
+            .unwrap_or(ranking.len())
+    });
+}
@@ -0,0 +1,92 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+use chrono::{Local, Timelike};
+use tokio::time::Instant;
+
+/// A daily window with its own limit, in minutes since local midnight. A
+/// window whose end is before its start runs past midnight.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScheduleWindow {
+    pub start_minute: u32,
+    pub end_minute: u32,
+    pub bytes_per_second: Option<u64>,
+}
+
+impl ScheduleWindow {
+    fn contains(&self, minute: u32) -> bool {
+        if self.start_minute <= self.end_minute {
+            (self.start_minute..self.end_minute).contains(&minute)
+        } else {
+            minute >= self.start_minute || minute < self.end_minute
+        }
+    }
+}
+
+/// Bytes per second allowed, `None` meaning unlimited, optionally varying by
+/// time of day. The first window covering the current time wins.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RateLimit {
+    pub bytes_per_second: Option<u64>,
+    pub schedule: Vec<ScheduleWindow>,
+}
+
+impl RateLimit {
+    pub fn at_minute(&self, minute: u32) -> Option<u64> {
+        self.schedule
+            .iter()
+            .find(|window| window.contains(minute))
+            .map_or(self.bytes_per_second, |window| window.bytes_per_second)
+    }
+
+    fn current(&self) -> Option<u64> {
+        let now = Local::now();
+        self.at_minute(now.hour() * 60 + now.minute())
+    }
+}
+
+struct LimiterState {
+    limit: RateLimit,
+    /// When the bytes reserved so far will have been paid for.
+    next_free: Instant,
+}
+
+/// Paces transfers to a rate limit. Reservations are handed out in the order
+/// they're made, so concurrent transfers share the rate chunk by chunk.
+pub struct RateLimiter {
+    state: Mutex<LimiterState>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            state: Mutex::new(LimiterState {
+                limit,
+                next_free: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn set_limit(&self, limit: RateLimit) {
+        let mut state = self.state.lock().unwrap();
+        state.limit = limit;
+        // Debt built up under the old limit shouldn't hold back the new one
+        state.next_free = Instant::now();
+    }
+
+    /// Reserves `bytes` and returns how long the caller must wait before
+    /// using them.
+    pub fn reserve(&self, bytes: u64) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let rate = match state.limit.current() {
+            Some(rate) => rate.max(1),
+            None => return Duration::ZERO,
+        };
+
+        // Idle time doesn't bank credit for a later burst
+        let now = Instant::now();
+        let start = state.next_free.max(now);
+        state.next_free = start + Duration::from_secs_f64(bytes as f64 / rate as f64);
+        start - now
+    }
+}
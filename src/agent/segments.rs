@@ -0,0 +1,81 @@
+const SEGMENTS_HEADER: &str = "# scarlet segments v1";
+
+/// A byte range of a file fetched on its own connection. `end` is exclusive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment {
+    pub start: u64,
+    pub end: u64,
+    /// Bytes already written from `start`.
+    pub done: u64,
+}
+
+impl Segment {
+    pub fn size(&self) -> u64 {
+        self.end - self.start
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.done >= self.size()
+    }
+}
+
+/// Splits `size` bytes into at most `count` contiguous segments.
+pub fn split(size: u64, count: usize) -> Vec<Segment> {
+    let count = (count.max(1) as u64).min(size.max(1));
+    let segment_size = size.div_ceil(count);
+
+    (0..count)
+        .map(|i| i * segment_size)
+        .take_while(|start| *start < size || size == 0)
+        .map(|start| Segment {
+            start,
+            end: (start + segment_size).min(size),
+            done: 0,
+        })
+        .collect()
+}
+
+/// Segment progress as written next to a partial file.
+pub fn format(size: u64, segments: &[Segment]) -> String {
+    let mut contents = format!("{}\n{}\n", SEGMENTS_HEADER, size);
+    for segment in segments {
+        contents.push_str(&format!(
+            "{} {} {}\n",
+            segment.start, segment.end, segment.done
+        ));
+    }
+    contents
+}
+
+/// Reads back segment progress, or `None` if it's unreadable or was written
+/// for a file of a different size.
+pub fn parse(contents: &str, size: u64) -> Option<Vec<Segment>> {
+    let mut lines = contents.lines();
+    if lines.next()? != SEGMENTS_HEADER || lines.next()?.parse::<u64>().ok()? != size {
+        return None;
+    }
+
+    let mut segments = Vec::new();
+    for line in lines {
+        let mut fields = line.split(' ').map(|field| field.parse::<u64>());
+        let segment = Segment {
+            start: fields.next()?.ok()?,
+            end: fields.next()?.ok()?,
+            done: fields.next()?.ok()?,
+        };
+        if segment.start > segment.end || segment.end > size || segment.done > segment.size() {
+            return None;
+        }
+        segments.push(segment);
+    }
+
+    // The ranges must tile the whole file or the result can't be trusted
+    let mut expected_start = 0;
+    for segment in &segments {
+        if segment.start != expected_start {
+            return None;
+        }
+        expected_start = segment.end;
+    }
+    (expected_start == size).then_some(segments)
+}
@@ -2,19 +2,49 @@ import {BrowserWindow, ipcMain, shell, dialog, App} from 'electron';
 import * as path from "path";
 import * as fs from "fs";
 import {autoUpdater} from "electron-updater";
-import {fetchAndConvertXML, getKeywordArguments} from './utils';
-import {FileDownload} from './types';
+import {getKeywordArguments} from './utils';
+import {CleanupEntry, DownloadEvent, DownloadOptions, FileDownload, JobInfo, MirrorProbe, QuarantineSnapshot, RateLimit, SyncPlan, SyncSummary} from './types';
 
 const {
     ping,
     get_progress,
+    plan_download,
+    fetch_manifest,
+    probe_mirrors,
     start_download,
-    stop_download
+    wait_for_job,
+    list_jobs,
+    remove_job,
+    list_quarantine,
+    restore_quarantine,
+    preview_cleanup,
+    run_cleanup,
+    stop_download,
+    pause_download,
+    resume_download,
+    set_rate_limit,
+    subscribe_progress,
+    unsubscribe_progress
 }: {
     ping: () => void,
-    get_progress: () => Promise<any>,
-    start_download: (destination_path: string, files: Array<FileDownload>) => Promise<any>,
-    stop_download: () => void
+    get_progress: (job_id?: number) => Promise<any>,
+    plan_download: (destination_path: string, files: Array<FileDownload>) => Promise<SyncPlan>,
+    fetch_manifest: (url: string) => Promise<Array<FileDownload>>,
+    probe_mirrors: (files: Array<FileDownload>, options?: DownloadOptions) => Promise<Array<MirrorProbe>>,
+    start_download: (destination_path: string, files: Array<FileDownload>, options?: DownloadOptions) => number,
+    wait_for_job: (job_id: number) => Promise<SyncSummary>,
+    list_jobs: () => Promise<Array<JobInfo>>,
+    remove_job: (job_id: number) => void,
+    list_quarantine: (destination_path: string) => Promise<Array<QuarantineSnapshot>>,
+    restore_quarantine: (destination_path: string, snapshot_id: string, paths?: Array<string>) => Promise<Array<string>>,
+    preview_cleanup: (destination_path: string, files: Array<FileDownload>) => Promise<Array<CleanupEntry>>,
+    run_cleanup: (destination_path: string, files: Array<FileDownload>, approved_paths?: Array<string>, options?: DownloadOptions) => Promise<Array<string>>,
+    stop_download: (job_id?: number) => void,
+    pause_download: (job_id?: number) => Promise<boolean>,
+    resume_download: (job_id?: number) => Promise<boolean>,
+    set_rate_limit: (limit: RateLimit | null, job_id?: number) => void,
+    subscribe_progress: (callback: (event: DownloadEvent) => void, interval_ms?: number) => number,
+    unsubscribe_progress: (subscription_id: number) => void
 } = require('./agent.node');
 
 export default class Main {
@@ -207,17 +237,68 @@ export default class Main {
 
         ipcMain.handle('ping', ping);
 
-        ipcMain.handle('stop_download', stop_download);
-        ipcMain.handle('get_progress', get_progress);
+        subscribe_progress((event: DownloadEvent) => {
+            if (Main.mainWindow) {
+                Main.mainWindow.webContents.send('download_event', event);
+            }
+        });
 
-        ipcMain.handle('start_download', async (
+        ipcMain.handle('stop_download', (evt, job_id?: number) => stop_download(job_id));
+        ipcMain.handle('pause_download', (evt, job_id?: number) => pause_download(job_id));
+        ipcMain.handle('resume_download', (evt, job_id?: number) => resume_download(job_id));
+        ipcMain.handle('set_rate_limit', (evt, limit: RateLimit | null, job_id?: number) => set_rate_limit(limit, job_id));
+        ipcMain.handle('get_progress', (evt, job_id?: number) => get_progress(job_id));
+        ipcMain.handle('wait_for_job', (evt, job_id: number) => wait_for_job(job_id));
+        ipcMain.handle('list_jobs', list_jobs);
+        ipcMain.handle('remove_job', (evt, job_id: number) => remove_job(job_id));
+        ipcMain.handle('list_quarantine', (evt, destination_folder: string) => list_quarantine(destination_folder));
+        ipcMain.handle('restore_quarantine', (
+            evt,
+            destination_folder: string,
+            snapshot_id: string,
+            paths?: Array<string>
+        ) => restore_quarantine(destination_folder, snapshot_id, paths));
+        ipcMain.handle('preview_cleanup', (
+            evt,
+            destination_folder: string,
+            files: Array<FileDownload>
+        ) => preview_cleanup(destination_folder, files));
+        ipcMain.handle('run_cleanup', (
+            evt,
+            destination_folder: string,
+            files: Array<FileDownload>,
+            approved_paths?: Array<string>,
+            options?: DownloadOptions
+        ) => run_cleanup(destination_folder, files, approved_paths, options));
+
+        ipcMain.handle('plan_download', async (
             evt,
             destination_folder: string,
             files: Array<FileDownload>
+        ) => {
+            return plan_download(destination_folder, files);
+        });
+
+        ipcMain.handle('fetch_manifest', (evt, url: string) => fetch_manifest(url));
+
+        ipcMain.handle('probe_mirrors', async (
+            evt,
+            files: Array<FileDownload>,
+            options?: DownloadOptions
+        ) => {
+            return probe_mirrors(files, options);
+        });
+
+        ipcMain.handle('start_download', async (
+            evt,
+            destination_folder: string,
+            files: Array<FileDownload>,
+            options?: DownloadOptions
         ) => {
             return start_download(
                 destination_folder,
-                files
+                files,
+                options
             );
         })
     }
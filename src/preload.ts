@@ -1,4 +1,4 @@
-import {FileDownload} from "./types";
+import {DownloadOptions, FileDownload, RateLimit} from "./types";
 
 const { contextBridge, ipcRenderer } = require('electron')
 
@@ -28,10 +28,24 @@ contextBridge.exposeInMainWorld('scarlet', {
     /**
      * Rust Bindings
      */
-    start_download: (destination_folder: string, files: Array<FileDownload>) => ipcRenderer.invoke("start_download", destination_folder, files),
-    stop_download: () => ipcRenderer.invoke('stop_download'),
-    get_progress: () => ipcRenderer.invoke('get_progress'),
+    start_download: (destination_folder: string, files: Array<FileDownload>, options?: DownloadOptions) => ipcRenderer.invoke("start_download", destination_folder, files, options),
+    plan_download: (destination_folder: string, files: Array<FileDownload>) => ipcRenderer.invoke("plan_download", destination_folder, files),
+    fetch_manifest: (url: string) => ipcRenderer.invoke("fetch_manifest", url),
+    probe_mirrors: (files: Array<FileDownload>, options?: DownloadOptions) => ipcRenderer.invoke("probe_mirrors", files, options),
+    wait_for_job: (job_id: number) => ipcRenderer.invoke('wait_for_job', job_id),
+    list_jobs: () => ipcRenderer.invoke('list_jobs'),
+    remove_job: (job_id: number) => ipcRenderer.invoke('remove_job', job_id),
+    list_quarantine: (destination_folder: string) => ipcRenderer.invoke('list_quarantine', destination_folder),
+    restore_quarantine: (destination_folder: string, snapshot_id: string, paths?: Array<string>) => ipcRenderer.invoke('restore_quarantine', destination_folder, snapshot_id, paths),
+    preview_cleanup: (destination_folder: string, files: Array<FileDownload>) => ipcRenderer.invoke('preview_cleanup', destination_folder, files),
+    run_cleanup: (destination_folder: string, files: Array<FileDownload>, approved_paths?: Array<string>, options?: DownloadOptions) => ipcRenderer.invoke('run_cleanup', destination_folder, files, approved_paths, options),
+    stop_download: (job_id?: number) => ipcRenderer.invoke('stop_download', job_id),
+    pause_download: (job_id?: number) => ipcRenderer.invoke('pause_download', job_id),
+    resume_download: (job_id?: number) => ipcRenderer.invoke('resume_download', job_id),
+    set_rate_limit: (limit: RateLimit | null, job_id?: number) => ipcRenderer.invoke('set_rate_limit', limit, job_id),
+    get_progress: (job_id?: number) => ipcRenderer.invoke('get_progress', job_id),
     ping: () => ipcRenderer.invoke("ping"),
+    on_download_event: (callback: (_: any, event: any) => any) => ipcRenderer.on('download_event', callback),
 
     /**
      * Update Events
@@ -5,5 +5,144 @@ export interface FileDownload {
     url: string;
     path: string;
     sha256_hash: string;
+    size?: number;
+    /** Seconds since the Unix epoch, when the manifest records it */
+    mtime?: number;
+    mirrors?: string[];
 }
 
+/**
+ * Options for a download job
+ */
+export interface DownloadOptions {
+    concurrency?: number;
+    hashCache?: boolean;
+    forceRehash?: boolean;
+    rateLimit?: RateLimit;
+    mirrors?: string[];
+    probeMirrors?: boolean;
+    segmentThreshold?: number | null;
+    segments?: number;
+    segmentAcrossMirrors?: boolean;
+    timeouts?: {
+        connectMs?: number;
+        firstByteMs?: number;
+        stallMs?: number;
+    };
+    retry?: {
+        maxAttempts?: number;
+        initialBackoffMs?: number;
+        maxBackoffMs?: number;
+    };
+    /** Remove files the manifest no longer lists after each sync, true by default */
+    autoCleanup?: boolean;
+    /** What happens to files the manifest no longer lists, "delete" by default */
+    cleanupPolicy?: 'delete' | 'trash' | 'quarantine';
+    /** Quarantine snapshots older than this are deleted, 7 by default */
+    quarantineRetentionDays?: number;
+}
+
+/**
+ * Something cleanup would remove, in removal order
+ */
+export interface CleanupEntry {
+    path: string;
+    isDirectory: boolean;
+    /** For a directory, the total size of the files removed beneath it */
+    size: number;
+    /** The managed mod folder the path belongs to */
+    managedFolder: string;
+}
+
+/**
+ * Files moved aside by one quarantining cleanup
+ */
+export interface QuarantineSnapshot {
+    id: string;
+    /** Milliseconds since the Unix epoch */
+    createdAt: number;
+    files: Array<{ path: string; size: number }>;
+}
+
+/**
+ * Transfer rate cap in bytes per second, null or missing meaning unlimited.
+ * The first schedule window covering the local time overrides the default.
+ */
+export interface RateLimit {
+    bytesPerSecond?: number | null;
+    schedule?: Array<{
+        from: string;
+        to: string;
+        bytesPerSecond?: number | null;
+    }>;
+}
+
+/**
+ * Result of comparing a manifest against the install directory
+ */
+export interface SyncPlan {
+    upToDate: string[];
+    missing: string[];
+    mismatched: string[];
+    extra: string[];
+    /** Entries whose path is unsafe to write, which sync will fail */
+    rejected: FileError[];
+    upToDateBytes: number;
+    mismatchedBytes: number;
+    extraBytes: number;
+}
+
+/**
+ * Pushed from the agent while a download is running, tagged with its job
+ */
+export type DownloadEvent = { jobId: number } & (
+    | { type: 'progress', progress: any }
+    | { type: 'fileStarted' | 'verificationStarted' | 'fileCompleted' | 'cleanupRemoved', path: string }
+    | { type: 'fileFailed', path: string, error: string }
+    | { type: 'hashIndexFailed', error: string }
+    | { type: 'syncFinished', status: string, error?: string }
+);
+
+/**
+ * A download job known to the agent, running or finished
+ */
+export interface JobInfo {
+    jobId: number;
+    destination: string;
+    finished: boolean;
+    progress: any;
+    mirrorProbes: MirrorProbe[];
+}
+
+/**
+ * Latency and throughput measured against one mirror
+ */
+export interface MirrorProbe {
+    mirror: string;
+    latencyMs: number | null;
+    bytesPerSecond: number | null;
+    error: string | null;
+}
+
+/**
+ * A file that could not be synchronised, with a machine-readable error code
+ */
+export interface FileError {
+    path: string;
+    code: string;
+    message: string;
+}
+
+/**
+ * Result of a completed download job
+ */
+export interface SyncSummary {
+    downloaded: number;
+    skipped: number;
+    failed: number;
+    removed: number;
+    bytesTransferred: number;
+    durationMs: number;
+    errors: FileError[];
+    servedBy: Record<string, string>;
+}